@@ -0,0 +1,207 @@
+// compares the arena backed structure with the Rc<RefCell<Node>> layout it replaced
+// run with `cargo bench --bench arena`
+//
+// both sides build the same million node graph, every node i > 0 is a child of node (i - 1) / 2,
+// then answer has_child_by_key for every edge, the arena also answers has_child with the ids add_node handed back
+// memory is the number of heap bytes and allocations still live once the graph is built, counted by the allocator below
+// a second run links FAN_OUT nodes under a single hub, where has_child_by_key used to scan every child
+//
+// on a single core the arena builds the tree and answers has_child_by_key about as fast as the Rc layout,
+// from level to a fifth slower between runs, since both keys of every question still go through the
+// ordered key map, only has_child by id is clearly faster, by more than ten times, and the heap is a sixth smaller
+// under the hub the arena builds slower and takes a little more heap, what it wins there is not scanning the children
+
+use maprootdb::{NewNode, Structure, UnStrict};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const NODES: usize = 1_000_000;
+const FAN_OUT: usize = 20_000;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// the layout the structure used before the arena, kept here only to measure against
+#[derive(Clone)]
+struct RcNodeRef(Rc<RefCell<RcNode>>);
+
+struct RcNode {
+    key: String,
+    value: u64,
+    parents: HashSet<RcNodeRef>,
+    children: HashSet<RcNodeRef>,
+}
+
+impl Hash for RcNodeRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.borrow().key.hash(state);
+    }
+}
+
+impl PartialEq for RcNodeRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.borrow().key == other.0.borrow().key
+    }
+}
+
+impl Eq for RcNodeRef {}
+
+impl RcNodeRef {
+    fn key(&self) -> String {
+        self.0.borrow().key.clone()
+    }
+
+    fn has_child_by_key(&self, key: &str) -> bool {
+        self.0.borrow().children.iter().any(|child| child.key() == key)
+    }
+}
+
+fn keys(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("node:{}", i)).collect()
+}
+
+struct Report {
+    build: Duration,
+    lookups: Duration,
+    id_lookups: Option<Duration>, // only the arena has ids to look up by
+    bytes: usize,
+    allocations: usize,
+}
+
+fn live() -> (usize, usize) {
+    (ALLOCATED.load(Ordering::Relaxed), ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+// node i > 0 hangs under node (i - 1) / 2 for the tree, or under node 0 for the fan out
+fn parent_of(i: usize, fan_out: bool) -> usize {
+    if fan_out { 0 } else { (i - 1) / 2 }
+}
+
+fn bench_arena(keys: &[String], fan_out: bool) -> Report {
+    // the ids are kept by the bench, not the structure, so they are left out of the heap count
+    let mut ids = Vec::with_capacity(keys.len());
+    let (bytes_before, allocations_before) = live();
+    let start = Instant::now();
+    let mut structure: Structure<u64> = Structure::new(None, UnStrict);
+    for (i, key) in keys.iter().enumerate() {
+        let mut node = NewNode::new(key.clone(), i as u64);
+        if i > 0 {
+            node.add_parent(&keys[parent_of(i, fan_out)]);
+        }
+        ids.push(structure.add_node(node).unwrap());
+    }
+    let build = start.elapsed();
+    let (bytes, allocations) = live();
+
+    let start = Instant::now();
+    let mut found = 0;
+    for i in 1..keys.len() {
+        if structure.has_child_by_key(&keys[parent_of(i, fan_out)], &keys[i]) {
+            found += 1;
+        }
+    }
+    let lookups = start.elapsed();
+    assert_eq!(found, keys.len() - 1);
+
+    let start = Instant::now();
+    let found = (1..ids.len()).filter(|i| structure.has_child(ids[parent_of(*i, fan_out)], ids[*i])).count();
+    let id_lookups = Some(start.elapsed());
+    assert_eq!(found, keys.len() - 1);
+    Report { build, lookups, id_lookups, bytes: bytes - bytes_before, allocations: allocations - allocations_before }
+}
+
+fn bench_rc(keys: &[String], fan_out: bool) -> Report {
+    let (bytes_before, allocations_before) = live();
+    let start = Instant::now();
+    let mut nodes: HashMap<String, RcNodeRef> = HashMap::new();
+    for (i, key) in keys.iter().enumerate() {
+        let node = RcNodeRef(Rc::new(RefCell::new(RcNode { key: key.clone(), value: i as u64, parents: HashSet::new(), children: HashSet::new() })));
+        if i > 0 {
+            let parent = nodes[&keys[parent_of(i, fan_out)]].clone();
+            node.0.borrow_mut().parents.insert(parent.clone());
+            parent.0.borrow_mut().children.insert(node.clone());
+        }
+        nodes.insert(key.clone(), node);
+    }
+    let build = start.elapsed();
+    let (bytes, allocations) = live();
+
+    let start = Instant::now();
+    let mut found = 0;
+    for i in 1..keys.len() {
+        if nodes[&keys[parent_of(i, fan_out)]].has_child_by_key(&keys[i]) {
+            found += 1;
+        }
+    }
+    let lookups = start.elapsed();
+    assert_eq!(found, keys.len() - 1);
+    assert!(nodes.values().all(|node| node.0.borrow().value < keys.len() as u64));
+
+    // parent and child links form Rc cycles, break them so the graph is actually freed
+    for node in nodes.values() {
+        let mut node = node.0.borrow_mut();
+        node.parents.clear();
+        node.children.clear();
+    }
+    Report { build, lookups, id_lookups: None, bytes: bytes - bytes_before, allocations: allocations - allocations_before }
+}
+
+fn print(name: &str, report: &Report) {
+    let id_lookups = report.id_lookups.map_or_else(|| "-".to_string(), |time| format!("{:.2?}", time));
+    println!(
+        "  {:<6} build {:>9.2?}   has_child_by_key {:>9.2?}   has_child {:>9}   heap {:>7.1} MiB in {:>8} allocations",
+        name,
+        report.build,
+        report.lookups,
+        id_lookups,
+        report.bytes as f64 / (1024.0 * 1024.0),
+        report.allocations
+    );
+}
+
+fn main() {
+    // cargo passes --bench to every bench target, anything else (like cargo test --benches) only needs it to run quickly
+    let quick = !std::env::args().any(|arg| arg == "--bench");
+    if quick {
+        return;
+    }
+    let keys = keys(NODES);
+    println!("{} nodes in a binary tree", NODES);
+    print("arena", &bench_arena(&keys, false));
+    print("rc", &bench_rc(&keys, false));
+
+    let keys = self::keys(FAN_OUT + 1);
+    println!("{} nodes under one hub", FAN_OUT);
+    print("arena", &bench_arena(&keys, true));
+    print("rc", &bench_rc(&keys, true));
+}
@@ -1,50 +1,439 @@
 // this is the structure that uses the structure module to build out the database.
 
-use crate::structure::{self, Structure}; 
-use crate::node::NodeRef; 
-use std::collections::HashMap;  
-use bincode::{serialize, deserialize}; 
+use crate::structure::Structure; 
+use crate::error::{MapRootError, Result}; 
+use crate::policy::PolicyRegistry; 
+use crate::index::IndexValue; 
+use crate::node::{Edge, NewNode, NodeId}; 
+use crate::wal::{WalOp, WriteAheadLog}; 
+use crate::format::{self, decode, encode, FileKind, FormatError, ValueTag}; 
+use serde::{Serialize, Deserialize}; 
+use serde::de::DeserializeOwned; 
+use std::fs::{self, File}; 
+use std::io::{self, Write}; 
+use std::path::{Path, PathBuf}; 
+use std::sync::Arc; 
 
+// bumped whenever the layout of the database file changes
+// version 2 added the sequence number of the last logged operation the file contains
+// version 3 moved the file into the checksummed container described in format.rs
+pub const DATABASE_FORMAT_VERSION: u32 = 3; 
 
-pub struct PrimInitDatabase<T: Clone>{
-    pub data: Vec<PrimInitStructureWrapper<T>>, 
+// where one structure lives inside the database file
+// the offset is where its record starts in the file and len is the length of the snapshot it holds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry{
+    pub name: String, 
+    pub offset: u64, 
+    pub len: u64, 
+}
 
+// when the database folds its log into a new snapshot on its own
+// a limit that is None never triggers, so the default only checkpoints when asked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CheckpointPolicy{
+    pub max_log_bytes: Option<u64>, 
+    pub max_log_ops: Option<u64>, 
 }
-impl<T: Clone> PrimInitDatabase<T>{
-    pub fn new() -> Self {
-        PrimInitDatabase { data: Vec::new()}
+
+impl CheckpointPolicy{
+    pub(crate) fn is_due(&self, wal: &WriteAheadLog) -> bool{
+        self.max_log_bytes.is_some_and(|max| wal.len() >= max) || self.max_log_ops.is_some_and(|max| wal.ops() >= max)
+    }
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()>{
+    // write to a temporary file next to the target and rename it over the target once it is synced,
+    // so a reader only ever sees the old file or the complete new one
+    let mut tmp = path.as_os_str().to_os_string(); 
+    tmp.push(".tmp"); 
+    let tmp = PathBuf::from(tmp); 
+
+    let mut file = File::create(&tmp)?; 
+    file.write_all(bytes)?; 
+    file.sync_all()?; 
+    drop(file); 
+    fs::rename(&tmp, path)?; 
+
+    // make the rename itself durable, not every platform allows opening a directory so this is best effort
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()){
+        if let Ok(dir) = File::open(dir){
+            let _ = dir.sync_all(); 
+        }
+    }
+    Ok(())
+}
+
+fn encode_database<T: Clone + Eq + Serialize + ValueTag>(structures: &[(&str, &Structure<T>)], checkpoint_lsn: u64) -> Result<Vec<u8>>{
+    // the file is a container holding the catalog record followed by one record per structure snapshot
+    // the catalog has to know where every record lands, so the snapshots are framed first and appended after it
+    let mut catalog: Vec<CatalogEntry> = Vec::new(); 
+    let mut data: Vec<u8> = Vec::new(); 
+    for (name, structure) in structures{
+        let snapshot = structure.serialize_snapshot()?; 
+        catalog.push(CatalogEntry { name: name.to_string(), offset: data.len() as u64, len: snapshot.len() as u64 }); 
+        format::write_record(&mut data, &snapshot)?; 
+    }
+
+    let mut file: Vec<u8> = Vec::new(); 
+    format::write_header(&mut file, FileKind::Database, &format::value_type_tag::<T>()); 
+    // the catalog record has a fixed size for a given catalog, so encode it once to learn where the data starts
+    let data_start = file.len() + 8 + encode(&(DATABASE_FORMAT_VERSION, checkpoint_lsn, &catalog), "database catalog")?.len(); 
+    for entry in catalog.iter_mut(){
+        entry.offset += data_start as u64; 
+    }
+    let header: (u32, u64, Vec<CatalogEntry>) = (DATABASE_FORMAT_VERSION, checkpoint_lsn, catalog); 
+    format::write_record(&mut file, &encode(&header, "database catalog")?)?; 
+    file.extend_from_slice(&data); 
+    Ok(file)
+}
+
+// the log of a database file sits next to it with .wal appended to the name
+pub fn wal_path<P: AsRef<Path>>(path: P) -> PathBuf{
+    let mut name = path.as_ref().as_os_str().to_os_string(); 
+    name.push(".wal"); 
+    PathBuf::from(name)
+}
+
+
+// everything a database keeps about its file and log
+// PrimInitDatabase and SharedDatabase both keep one and go through it to log, save and checkpoint
+pub(crate) struct LogState{
+    pub(crate) path: Option<PathBuf>,      // the file the database was opened from
+    pub(crate) wal: Option<WriteAheadLog>, // only present for a database that was opened from disk
+    pub(crate) policy: CheckpointPolicy, 
+    pub(crate) failed: bool,               // a change made it into memory but not into the log, see append
+    pub(crate) checkpoint_error: Option<Arc<MapRootError>>, // why the last automatic checkpoint failed, until one goes through
+}
+
+impl LogState{
+    pub(crate) fn new() -> Self{
+        LogState { path: None, wal: None, policy: CheckpointPolicy::default(), failed: false, checkpoint_error: None }
+    }
+
+    pub(crate) fn usable(&self) -> Result<()>{
+        if self.failed{
+            return Err(MapRootError::LogFailed); 
+        }
+        Ok(())
+    }
+
+    pub(crate) fn append<T: Serialize>(&mut self, op: &WalOp<T>) -> Result<bool>{
+        // log an operation that was already applied in memory and tell the caller whether a checkpoint is due
+        // if it can not be logged every later write is refused, so the unlogged change never reaches a snapshot
+        // either and reopening goes back to what is on disk
+        self.usable()?; 
+        let Some(wal) = &mut self.wal else { return Ok(false) }; 
+        if let Err(e) = wal.append(op){
+            self.failed = true; 
+            return Err(e); 
+        }
+        Ok(self.policy.is_due(wal))
+    }
+
+    pub(crate) fn save<T: Clone + Eq + Serialize + ValueTag>(&mut self, path: &Path, structures: &[(&str, &Structure<T>)]) -> Result<()>{
+        // write every structure into a single file, replacing whatever was there in one step
+        // saving over the file the database was opened from is the same as a checkpoint
+        if self.path.as_deref() == Some(path){
+            return self.checkpoint(structures); 
+        }
+        self.usable()?; 
+        let last_lsn = self.wal.as_ref().map_or(0, |wal| wal.last_lsn()); 
+        write_atomically(path, &encode_database(structures, last_lsn)?)?; 
+        Ok(())
+    }
+
+    pub(crate) fn checkpoint<T: Clone + Eq + Serialize + ValueTag>(&mut self, structures: &[(&str, &Structure<T>)]) -> Result<()>{
+        // fold the log into a fresh snapshot of the database file and empty the log
+        // the snapshot records the last sequence number it covers, so a crash between the rename
+        // and the truncation only leaves records behind that the next open skips
+        let Some(path) = self.path.clone() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "database was not opened from a file").into()); 
+        }; 
+        self.usable()?; 
+        let last_lsn = self.wal.as_ref().map_or(0, |wal| wal.last_lsn()); 
+        write_atomically(&path, &encode_database(structures, last_lsn)?)?; 
+        if let Some(wal) = &mut self.wal{
+            wal.reset()?; 
+        }
+        self.checkpoint_error = None; 
+        Ok(())
+    }
+}
+
+// what running an operation against a structure gives back, the handles unwrap the kind their method returns
+pub(crate) enum Applied<T: Clone>{
+    Done, 
+    Id(NodeId), 
+    Removed(NewNode<T>), 
+}
+
+impl<T: Clone> Applied<T>{
+    pub(crate) fn id(self) -> NodeId{
+        match self{
+            Applied::Id(id) => id, 
+            _ => unreachable!("adding, upserting and replacing a node give back its id"), 
+        }
     }
-    pub fn add_structure(&mut self, structure: PrimInitStructureWrapper<T>){
+
+    pub(crate) fn removed(self) -> NewNode<T>{
+        match self{
+            Applied::Removed(node) => node, 
+            _ => unreachable!("removing a node gives it back"), 
+        }
+    }
+}
+
+pub(crate) fn execute<T: Clone + Eq + Serialize>(structure: &mut Structure<T>, op: &WalOp<T>) -> Result<Applied<T>>{
+    // run one operation against the structure it names, the single place both database handles and replay
+    // turn an operation into a change, so what is logged is always exactly what was run
+    let applied = match op.clone(){
+        WalOp::AddNode { key, value, parents, children, edges, .. } => Applied::Id(structure.add_node(NewNode { key, value, parents, children, edges })?), 
+        WalOp::Upsert { key, value, parents, children, edges, .. } => Applied::Id(structure.upsert(NewNode { key, value, parents, children, edges })?), 
+        WalOp::Replace { key, value, parents, children, edges, .. } => Applied::Id(structure.replace(NewNode { key, value, parents, children, edges })?), 
+        WalOp::DeleteNode { key, .. } => { structure.delete_node_by_key(&key)?; Applied::Done }
+        WalOp::RemoveNode { key, .. } => Applied::Removed(structure.remove_node_by_key(&key)?), 
+        WalOp::Link { parent, child, .. } => { structure.link(&parent, &child)?; Applied::Done }
+        WalOp::LinkWith { parent, child, edge, .. } => { structure.link_with(&parent, &child, edge)?; Applied::Done }
+        WalOp::Unlink { parent, child, .. } => { structure.unlink(&parent, &child)?; Applied::Done }
+        WalOp::EditValue { key, value, .. } => { structure.edit_value(&key, value)?; Applied::Done }
+        WalOp::Rename { old_key, new_key, .. } => { structure.rename(&old_key, &new_key)?; Applied::Done }
+        WalOp::SetRoot { root, .. } => { structure.set_root(root.as_deref())?; Applied::Done }
+        WalOp::MoveChild { child, from, to, .. } => { structure.move_child(&child, &from, &to)?; Applied::Done }
+        WalOp::AddStructure { .. } => unreachable!("structures are added by the database, not run against another structure"), 
+    }; 
+    Ok(applied)
+}
+
+pub struct PrimInitDatabase<T: Clone>{
+    data: Vec<PrimInitStructureWrapper<T>>, 
+    log: LogState, 
+}
+
+impl<T: Clone + Eq + Serialize + ValueTag> PrimInitDatabase<T>{
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()>{
+        let structures: Vec<(&str, &Structure<T>)> = self.data.iter().map(|wrapper| (wrapper.name.as_str(), &wrapper.structure)).collect(); 
+        self.log.save(path.as_ref(), &structures)
+    }
+
+    pub fn checkpoint(&mut self) -> Result<()>{
+        let structures: Vec<(&str, &Structure<T>)> = self.data.iter().map(|wrapper| (wrapper.name.as_str(), &wrapper.structure)).collect(); 
+        self.log.checkpoint(&structures)
+    }
+
+    pub fn add_structure(&mut self, structure: PrimInitStructureWrapper<T>) -> Result<()>{
+        // structures are looked up by name so two of them can not share one
+        self.log.usable()?; 
+        if self.structure(&structure.name).is_some(){
+            return Err(MapRootError::DuplicateKey(structure.name)); 
+        }
+        let due = self.log.append(&WalOp::<T>::AddStructure { name: structure.name.clone(), snapshot: structure.structure.serialize_snapshot()? })?; 
         self.data.push(structure); 
+        self.checkpoint_if(due); 
+        Ok(())
+    }
+
+    pub fn add_node(&mut self, structure: &str, node: NewNode<T>) -> Result<NodeId>{
+        self.run(WalOp::add_node(structure, node)).map(Applied::id)
+    }
+
+    pub fn upsert(&mut self, structure: &str, node: NewNode<T>) -> Result<NodeId>{
+        self.run(WalOp::upsert(structure, node)).map(Applied::id)
+    }
+
+    pub fn replace(&mut self, structure: &str, node: NewNode<T>) -> Result<NodeId>{
+        self.run(WalOp::replace(structure, node)).map(Applied::id)
+    }
+
+    pub fn delete_node(&mut self, structure: &str, key: &str) -> Result<()>{
+        self.run(WalOp::delete_node(structure, key)).map(|_| ())
+    }
+
+    pub fn remove_node(&mut self, structure: &str, key: &str) -> Result<NewNode<T>>{
+        self.run(WalOp::remove_node(structure, key)).map(Applied::removed)
+    }
+
+    pub fn link(&mut self, structure: &str, parent: &str, child: &str) -> Result<()>{
+        self.run(WalOp::link(structure, parent, child)).map(|_| ())
+    }
+
+    pub fn link_with(&mut self, structure: &str, parent: &str, child: &str, edge: Edge) -> Result<()>{
+        self.run(WalOp::link_with(structure, parent, child, edge)).map(|_| ())
+    }
+
+    pub fn unlink(&mut self, structure: &str, parent: &str, child: &str) -> Result<()>{
+        self.run(WalOp::unlink(structure, parent, child)).map(|_| ())
+    }
+
+    pub fn edit_value(&mut self, structure: &str, key: &str, value: T) -> Result<()>{
+        self.run(WalOp::edit_value(structure, key, value)).map(|_| ())
+    }
+
+    pub fn rename(&mut self, structure: &str, old_key: &str, new_key: &str) -> Result<()>{
+        self.run(WalOp::rename(structure, old_key, new_key)).map(|_| ())
+    }
+
+    pub fn set_root(&mut self, structure: &str, root: Option<&str>) -> Result<()>{
+        self.run(WalOp::set_root(structure, root)).map(|_| ())
+    }
+
+    pub fn move_child(&mut self, structure: &str, child: &str, from: &str, to: &str) -> Result<()>{
+        self.run(WalOp::move_child(structure, child, from, to)).map(|_| ())
+    }
+
+    fn run(&mut self, op: WalOp<T>) -> Result<Applied<T>>{
+        // the change is made in memory first and only logged once it succeeds,
+        // so replaying the log always takes the same path as the original call
+        self.log.usable()?; 
+        let name = op.structure(); 
+        let wrapper = self.structure_mut(name).ok_or_else(|| MapRootError::UnknownStructure(name.to_string()))?; 
+        let applied = execute(&mut wrapper.structure, &op)?; 
+        let due = self.log.append(&op)?; 
+        self.checkpoint_if(due); 
+        Ok(applied)
     }
 
-    // return a given structure in a mutable form 
-    pub fn structure_mut(&mut self, name: &str) -> Option<&mut PrimInitStructureWrapper<T>>{
-        for s in &mut self.data{
-            if s.name == name{
-                let mut f = s; 
-                return Some(f); 
+    fn checkpoint_if(&mut self, due: bool){
+        // the operation is safe in the log, so a checkpoint that fails here is not its failure,
+        // the log keeps growing and the error is kept for last_checkpoint_error until a later checkpoint goes through
+        if due{
+            if let Err(e) = self.checkpoint(){
+                self.log.checkpoint_error = Some(Arc::new(e)); 
             }
         }
-        None
     }
-    
-    // do it in read-only or not mutable form 
-    pub fn structure(&self, name: &str) -> Option<&PrimInitStructureWrapper<T>>{
-        for s in &self.data{
-            if s.name == name{
-                return Some(s); 
+}
+
+impl<T: Clone + Eq + Serialize + DeserializeOwned + ValueTag + 'static> PrimInitDatabase<T>{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self>{
+        Self::open_with(path, &PolicyRegistry::default())
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, registry: &PolicyRegistry<T>) -> Result<Self>{
+        // load a database file written by save and replay its log on top of it
+        // a database that has never been saved starts out empty
+        // from here on every mutation made through the database is logged next to the file
+        // structures using a custom policy are only loaded if the registry knows it
+        let (mut database, checkpoint_lsn) = match fs::read(&path){
+            Ok(file) => Self::load(&file, registry)?, 
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (PrimInitDatabase::new(), 0), 
+            Err(e) => return Err(e.into()), 
+        }; 
+
+        let (wal, ops) = WriteAheadLog::open::<T, _>(wal_path(&path), checkpoint_lsn)?; 
+        for (lsn, op) in ops{
+            database.apply(lsn, op, registry)?; 
+        }
+        database.log.path = Some(path.as_ref().to_path_buf()); 
+        database.log.wal = Some(wal); 
+        Ok(database)
+    }
+
+    fn load(file: &[u8], registry: &PolicyRegistry<T>) -> Result<(Self, u64)>{
+        let offset = format::read_header(file, FileKind::Database, &format::value_type_tag::<T>())?; 
+        let (catalog, _) = format::read_record(file, offset, "database catalog")?; 
+        let version: u32 = decode(catalog, "database catalog")?; 
+        if version != DATABASE_FORMAT_VERSION{
+            return Err(FormatError::UnsupportedVersion { what: "database format".to_string(), version }.into()); 
+        }
+        let (_, checkpoint_lsn, catalog): (u32, u64, Vec<CatalogEntry>) = decode(catalog, "database catalog")?; 
+
+        let mut database = PrimInitDatabase::new(); 
+        for entry in catalog{
+            let (snapshot, _) = format::read_record(file, entry.offset as usize, &format!("structure {}", entry.name))?; 
+            if snapshot.len() as u64 != entry.len{
+                return Err(FormatError::Invalid(format!("structure {} does not match its catalog entry", entry.name)).into()); 
             }
+            let structure = Structure::from_snapshot_with(snapshot, registry)?; 
+            database.data.push(PrimInitStructureWrapper::new(entry.name, structure)); 
         }
-        None
+        Ok((database, checkpoint_lsn))
     }
-    
 
-    
+    fn apply(&mut self, lsn: u64, op: WalOp<T>, registry: &PolicyRegistry<T>) -> Result<()>{
+        // redo one logged operation, only called while no log is attached
+        // only operations that succeeded were logged, so one that fails now means the log does not belong
+        // to the snapshot under it and the database is reported as corrupt instead of opened without it
+        let described = op.to_string(); 
+        let result = match op{
+            WalOp::AddStructure { name, snapshot } => Structure::from_snapshot_with(&snapshot, registry)
+                .and_then(|structure| self.add_structure(PrimInitStructureWrapper::new(name, structure))), 
+            op => self.run(op).map(|_| ()), 
+        }; 
+        match result{
+            Err(e @ MapRootError::Io(_)) => Err(e), 
+            Err(e) => Err(FormatError::Invalid(format!("log record {} ({}) no longer applies: {}", lsn, described, e)).into()), 
+            Ok(()) => Ok(()), 
+        }
+    }
+}
+
+impl<T: Clone> PrimInitDatabase<T>{
+    pub fn new() -> Self {
+        PrimInitDatabase { data: Vec::new(), log: LogState::new() }
+    }
 
+    pub fn checkpoint_policy(&self) -> CheckpointPolicy{
+        self.log.policy
+    }
+
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy){
+        self.log.policy = policy; 
+    }
 
+    pub fn last_checkpoint_error(&self) -> Option<&MapRootError>{
+        // the error of the last checkpoint the policy started, if no checkpoint went through since
+        self.log.checkpoint_error.as_deref()
+    }
+
+    // take the database apart so it can be handed to a SharedDatabase
+    pub(crate) fn into_parts(self) -> (Vec<PrimInitStructureWrapper<T>>, LogState){
+        (self.data, self.log)
+    }
+
+    // every change to a structure has to go through the database so it is logged, so outside the crate
+    // structures are only handed out read-only
+    pub(crate) fn structure_mut(&mut self, name: &str) -> Option<&mut PrimInitStructureWrapper<T>>{
+        self.data.iter_mut().find(|s| s.name == name)
+    }
     
+    pub fn structure(&self, name: &str) -> Option<&PrimInitStructureWrapper<T>>{
+        self.data.iter().find(|s| s.name == name)
+    }
 
+    pub fn structures(&self) -> &[PrimInitStructureWrapper<T>]{
+        &self.data
+    }
+
+    pub fn register_alt_key<F>(&mut self, structure: &str, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<String> + Send + Sync + 'static,
+    {
+        // indexes are not logged since the extractor can not be saved, register them again after every open
+        let target = self.structure_mut(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?; 
+        target.structure.register_alt_key(name, extractor)
+    }
+
+    pub fn register_index<F>(&mut self, structure: &str, name: &str, unique: bool, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<IndexValue> + Send + Sync + 'static,
+    {
+        let target = self.structure_mut(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?; 
+        target.structure.register_index(name, unique, extractor)
+    }
+
+    pub fn enable_reachability(&mut self, structure: &str) -> Result<()>{
+        // like indexes this is not logged, turn it on again after every open
+        let target = self.structure_mut(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?; 
+        target.structure.enable_reachability(); 
+        Ok(())
+    }
+}
+
+impl<T: Clone> Default for PrimInitDatabase<T>{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 
@@ -58,4 +447,205 @@ impl<T: Clone> PrimInitStructureWrapper<T>{
     pub fn new(name: String, structure: Structure<T>) -> Self{
         PrimInitStructureWrapper { name, structure}
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*; 
+    use crate::policy::UnStrict; 
+
+    fn scratch(name: &str) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("maprootdb-db-{}-{}", name, std::process::id())); 
+        let _ = fs::remove_dir_all(&dir); 
+        fs::create_dir_all(&dir).unwrap(); 
+        dir.join("test.mrdb")
+    }
+
+    fn with_structure(path: &Path) -> PrimInitDatabase<u32>{
+        let mut db: PrimInitDatabase<u32> = PrimInitDatabase::open(path).unwrap(); 
+        db.add_structure(PrimInitStructureWrapper::new("s".to_string(), Structure::new(None, UnStrict))).unwrap(); 
+        db
+    }
+
+    fn keys(db: &PrimInitDatabase<u32>) -> Vec<String>{
+        db.structure("s").unwrap().structure.keys().map(String::from).collect()
+    }
+
+    #[test]
+    fn checkpoint_then_reopen_keeps_everything(){
+        let path = scratch("checkpoint"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+        db.link("s", "a", "b").unwrap(); 
+        db.checkpoint().unwrap(); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+        db.add_node("s", NewNode::new("c".to_string(), 3)).unwrap(); 
+        drop(db); 
+
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(keys(&db), vec!["a", "b", "c"]); 
+        assert_eq!(db.structure("s").unwrap().structure.children("a")[0].key, "b"); 
+        // only the change after the checkpoint was left in the log
+        assert_eq!(db.log.wal.as_ref().unwrap().ops(), 1); 
+    }
+
+    #[test]
+    fn reopen_without_checkpoint_replays_the_log(){
+        let path = scratch("replay"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.edit_value("s", "a", 5).unwrap(); 
+        drop(db); 
+
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(db.structure("s").unwrap().structure.find_node_by_key("a").unwrap().value, 5); 
+    }
+
+    #[test]
+    fn failed_log_write_makes_the_database_refuse_changes(){
+        let path = scratch("failed"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.log.wal.as_mut().unwrap().break_writes(); 
+
+        assert!(matches!(db.add_node("s", NewNode::new("b".to_string(), 2)), Err(MapRootError::Io(_)))); 
+        assert!(matches!(db.add_node("s", NewNode::new("c".to_string(), 3)), Err(MapRootError::LogFailed))); 
+        assert!(matches!(db.checkpoint(), Err(MapRootError::LogFailed))); 
+        drop(db); 
+
+        // the change that never made it into the log is gone after reopening
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(keys(&db), vec!["a"]); 
+    }
+
+    #[test]
+    fn set_root_is_logged_and_replayed(){
+        let path = scratch("root"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+        db.set_root("s", Some("b")).unwrap(); 
+        assert!(matches!(db.set_root("s", Some("x")), Err(MapRootError::NotFound(_)))); 
+        drop(db); 
+
+        let mut db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(db.structure("s").unwrap().structure.root().unwrap().key, "b"); 
+        db.set_root("s", None).unwrap(); 
+        drop(db); 
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert!(db.structure("s").unwrap().structure.root().is_none()); 
+    }
+
+    #[test]
+    fn log_record_that_no_longer_applies_is_corruption(){
+        let path = scratch("stale"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.log.wal.as_mut().unwrap().append(&WalOp::<u32>::link("s", "a", "missing")).unwrap(); 
+        drop(db); 
+
+        let Err(MapRootError::Corrupt(FormatError::Invalid(message))) = PrimInitDatabase::<u32>::open(&path) else {
+            panic!("a log record that fails on replay has to fail the open"); 
+        }; 
+        assert!(message.starts_with("log record 3 (link a to missing in s)"), "{}", message); 
+    }
+
+    #[test]
+    fn checkpoint_policy_resets_the_log_once_a_limit_is_reached(){
+        let path = scratch("policy"); 
+        let mut db = with_structure(&path); 
+        db.set_checkpoint_policy(CheckpointPolicy { max_log_bytes: None, max_log_ops: Some(3) }); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        assert_eq!(db.log.wal.as_ref().unwrap().ops(), 2); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+
+        db.set_checkpoint_policy(CheckpointPolicy { max_log_bytes: Some(1), max_log_ops: None }); 
+        db.link("s", "a", "b").unwrap(); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+        drop(db); 
+
+        // the log is empty, so everything comes back from the database file
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(keys(&db), vec!["a", "b"]); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+    }
+
+    #[test]
+    fn failed_automatic_checkpoint_is_kept_until_one_goes_through(){
+        let path = scratch("policy-failed"); 
+        let mut db = with_structure(&path); 
+        db.set_checkpoint_policy(CheckpointPolicy { max_log_bytes: None, max_log_ops: Some(1) }); 
+        // a directory where the checkpoint wants its temporary file makes writing it fail
+        let tmp = path.with_extension("mrdb.tmp"); 
+        fs::create_dir(&tmp).unwrap(); 
+
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        assert!(matches!(db.last_checkpoint_error(), Some(MapRootError::Io(_)))); 
+        assert_eq!(db.log.wal.as_ref().unwrap().ops(), 2); 
+
+        fs::remove_dir(&tmp).unwrap(); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+        assert!(db.last_checkpoint_error().is_none()); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+    }
+
+    #[test]
+    fn save_to_another_path_copies_the_database_and_leaves_the_log_alone(){
+        let path = scratch("save"); 
+        let copy = path.with_file_name("copy.mrdb"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.save(&copy).unwrap(); 
+        assert_eq!(db.log.wal.as_ref().unwrap().ops(), 2); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+
+        // the copy holds what was there when it was saved and starts out with a log of its own
+        let mut saved: PrimInitDatabase<u32> = PrimInitDatabase::open(&copy).unwrap(); 
+        assert_eq!(keys(&saved), vec!["a"]); 
+        assert!(saved.log.wal.as_ref().unwrap().is_empty()); 
+        saved.add_node("s", NewNode::new("c".to_string(), 3)).unwrap(); 
+        drop(saved); 
+
+        // saving over the file the database came from is a checkpoint
+        db.save(&path).unwrap(); 
+        assert!(db.log.wal.as_ref().unwrap().is_empty()); 
+        drop(db); 
+        assert_eq!(keys(&PrimInitDatabase::open(&path).unwrap()), vec!["a", "b"]); 
+        assert_eq!(keys(&PrimInitDatabase::open(&copy).unwrap()), vec!["a", "c"]); 
+    }
+
+    #[test]
+    fn database_never_opened_from_a_file_can_be_saved_and_opened(){
+        let path = scratch("fresh"); 
+        let mut db: PrimInitDatabase<u32> = PrimInitDatabase::new(); 
+        db.add_structure(PrimInitStructureWrapper::new("s".to_string(), Structure::new(None, UnStrict))).unwrap(); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        assert!(matches!(db.checkpoint(), Err(MapRootError::Io(_)))); 
+        db.save(&path).unwrap(); 
+
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        assert_eq!(keys(&db), vec!["a"]); 
+        assert_eq!(db.structures().len(), 1); 
+    }
+
+    #[test]
+    fn labeled_edges_are_replayed_from_the_log(){
+        let path = scratch("labels"); 
+        let mut db = with_structure(&path); 
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap(); 
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap(); 
+        let mut c = NewNode::new("c".to_string(), 3); 
+        c.add_labeled_child("a", Edge::new("owns").with_payload(&"since 2020").unwrap()); 
+        db.add_node("s", c).unwrap(); 
+        db.link_with("s", "b", "a", Edge::new("follows")).unwrap(); 
+        drop(db); 
+
+        let db: PrimInitDatabase<u32> = PrimInitDatabase::open(&path).unwrap(); 
+        let structure = &db.structure("s").unwrap().structure; 
+        let owns = structure.edge("c", "a").unwrap().unwrap(); 
+        assert_eq!(owns.label, "owns"); 
+        assert_eq!(owns.payload::<String>().unwrap().as_deref(), Some("since 2020")); 
+        assert_eq!(structure.parents_labeled("a", "follows")[0].key, "b"); 
+    }
+}
@@ -0,0 +1,86 @@
+// the error type shared by every structure, node and database operation
+// each variant says what went wrong in a way a caller can act on, instead of a bare false
+// the neighbors of a strictness violation are the nodes that would be left breaking the rules,
+// they are empty when the node itself is what breaks them
+
+use crate::format::FormatError;
+use crate::traverse::Direction;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum MapRootError {
+    NotFound(String),          // the key is not part of the structure
+    UnknownStructure(String),  // the database has no structure with this name
+    EdgeNotFound { parent: String, child: String },
+    UnknownIndex(String),      // the structure has no index with this name
+    DuplicateKey(String),      // the key or structure name is already taken
+    StrictnessViolation { key: String, mode: String, offending_neighbors: Vec<String> },
+    Cycle(Vec<String>),        // the path the cycle would have taken, starting and ending on the same key
+    MultipleParents { key: String, parents: Vec<String> }, // a single parent was asked for but the node has several
+    ZeroLimit,                 // a page was asked for with a limit of zero, which could never make progress
+    UnsupportedDirection(Direction), // the query has no meaning when it follows edges this way
+    UnknownMode(String),       // the structure was created with a mode this version does not know
+    Corrupt(FormatError),      // bytes read back from disk or a snapshot could not be decoded
+    TypeMismatch { expected: String, found: String }, // the file holds values of another type, see ValueTag
+    Encode { what: String, message: String }, // bincode refused a value, or it is too large to fit a record
+    LogFailed,                 // a change could not be logged, so memory is ahead of the disk until the database is reopened
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, MapRootError>;
+
+impl fmt::Display for MapRootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapRootError::NotFound(key) => write!(f, "node {} is not part of the structure", key),
+            MapRootError::EdgeNotFound { parent, child } => write!(f, "there is no edge from {} to {}", parent, child),
+            MapRootError::UnknownIndex(name) => write!(f, "there is no index named {}", name),
+            MapRootError::UnknownStructure(name) => write!(f, "there is no structure named {}", name),
+            MapRootError::DuplicateKey(key) => write!(f, "{} is already taken", key),
+            MapRootError::StrictnessViolation { key, mode, offending_neighbors } if offending_neighbors.is_empty() => {
+                write!(f, "node {} breaks the {} rules of the structure", key, mode)
+            }
+            MapRootError::StrictnessViolation { key, mode, offending_neighbors } => {
+                write!(f, "node {} breaks the {} rules of the structure for {}", key, mode, offending_neighbors.join(", "))
+            }
+            MapRootError::MultipleParents { key, parents } => write!(f, "node {} has more than one parent: {}", key, parents.join(", ")),
+            MapRootError::ZeroLimit => write!(f, "a page needs a limit of at least one"),
+            MapRootError::UnsupportedDirection(direction) => write!(f, "the query can not follow {:?} edges", direction),
+            MapRootError::Cycle(path) => write!(f, "cycle through {}", path.join(" -> ")),
+            MapRootError::UnknownMode(mode) => write!(f, "unknown structure mode {}", mode),
+            MapRootError::Corrupt(e) => write!(f, "corrupt data: {}", e),
+            MapRootError::TypeMismatch { expected, found } => write!(f, "file holds values of type {} but {} was requested", found, expected),
+            MapRootError::Encode { what, message } => write!(f, "could not encode {}: {}", what, message),
+            MapRootError::LogFailed => write!(f, "an earlier change could not be written to the log, reopen the database"),
+            MapRootError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MapRootError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapRootError::Corrupt(e) => Some(e),
+            MapRootError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MapRootError {
+    fn from(e: io::Error) -> Self {
+        MapRootError::Io(e)
+    }
+}
+
+impl From<FormatError> for MapRootError {
+    fn from(e: FormatError) -> Self {
+        // a file of the wrong type and a record too large to write are not damage, so they get their own variants
+        match e {
+            FormatError::TypeMismatch { expected, found } => MapRootError::TypeMismatch { expected, found },
+            e @ FormatError::TooLarge { .. } => MapRootError::Encode { what: "record".to_string(), message: e.to_string() },
+            e => MapRootError::Corrupt(e),
+        }
+    }
+}
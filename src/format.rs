@@ -0,0 +1,315 @@
+// the container format shared by every file MapRootDb writes
+//
+// a file starts with a header
+//     magic       4 bytes  "MRDB"
+//     version     u16      FORMAT_VERSION, the version of this framing
+//     kind        u8       what the records hold, see FileKind
+//     tag length  u16      length of the value type tag
+//     tag         bytes    utf-8 name of the value type T the file was written with, see ValueTag
+//     checksum    u32      crc32 of every header byte before it
+//
+// and is followed by records until the end of the file
+//     length      u32      length of the payload, a payload can not be 4 GiB or larger
+//     checksum    u32      crc32 of the payload
+//     payload     bytes    bincode, its meaning depends on the kind of file
+//
+// all integers are little endian
+// every decoding step returns a FormatError describing what was wrong and where instead of panicking
+
+use bincode::{deserialize, serialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::MapRootError;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"MRDB";
+pub const FORMAT_VERSION: u16 = 1;
+
+const HEADER_FIXED_LEN: usize = 4 + 2 + 1 + 2;
+const RECORD_HEADER_LEN: usize = 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Database = 1,  // catalog record followed by one structure snapshot per record
+    Structure = 2, // structure header, ids, then one record per node
+    Log = 3,       // write-ahead log records
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    BadMagic,
+    UnsupportedVersion { what: String, version: u32 },
+    WrongKind { expected: FileKind, found: u8 },
+    TypeMismatch { expected: String, found: String },
+    Truncated { what: String, offset: usize },
+    ChecksumMismatch { what: String, offset: usize },
+    Decode { what: String, message: String },
+    TooLarge { len: usize }, // a payload too long for the length field of a record
+    Invalid(String), // the bytes decode but do not describe a valid structure or database
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "not a MapRootDb file, the magic bytes do not match"),
+            FormatError::UnsupportedVersion { what, version } => write!(f, "unsupported {} version {}", what, version),
+            FormatError::WrongKind { expected, found } => write!(f, "expected a {:?} file but found kind {}", expected, found),
+            FormatError::TypeMismatch { expected, found } => write!(f, "file holds values of type {} but {} was requested", found, expected),
+            FormatError::Truncated { what, offset } => write!(f, "{} is truncated at byte {}", what, offset),
+            FormatError::ChecksumMismatch { what, offset } => write!(f, "{} at byte {} fails its checksum", what, offset),
+            FormatError::Decode { what, message } => write!(f, "could not decode {}: {}", what, message),
+            FormatError::TooLarge { len } => write!(f, "a record of {} bytes is larger than the format allows", len),
+            FormatError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+// the name written into file headers for the value type, so a file is never read back as the wrong type
+// it has to stay the same across compiler versions and refactors, so it is spelled out by hand instead of
+// coming from std::any::type_name, value types of your own implement it with a name that does not change
+pub trait ValueTag {
+    fn value_tag() -> String;
+}
+
+macro_rules! value_tags {
+    ($($ty:ty => $tag:literal),* $(,)?) => {
+        $(impl ValueTag for $ty {
+            fn value_tag() -> String {
+                $tag.to_string()
+            }
+        })*
+    };
+}
+
+value_tags! {
+    () => "unit", bool => "bool", char => "char", String => "string",
+    u8 => "u8", u16 => "u16", u32 => "u32", u64 => "u64", u128 => "u128", usize => "usize",
+    i8 => "i8", i16 => "i16", i32 => "i32", i64 => "i64", i128 => "i128", isize => "isize",
+    f32 => "f32", f64 => "f64",
+}
+
+impl<T: ValueTag> ValueTag for Vec<T> {
+    fn value_tag() -> String {
+        format!("vec<{}>", T::value_tag())
+    }
+}
+
+impl<T: ValueTag> ValueTag for Option<T> {
+    fn value_tag() -> String {
+        format!("option<{}>", T::value_tag())
+    }
+}
+
+impl<T: ValueTag> ValueTag for Box<T> {
+    fn value_tag() -> String {
+        T::value_tag()
+    }
+}
+
+impl<K: ValueTag, V: ValueTag> ValueTag for BTreeMap<K, V> {
+    fn value_tag() -> String {
+        format!("map<{},{}>", K::value_tag(), V::value_tag())
+    }
+}
+
+impl<K: ValueTag, V: ValueTag, S> ValueTag for HashMap<K, V, S> {
+    fn value_tag() -> String {
+        // bincode writes both maps the same way, so they share a tag
+        format!("map<{},{}>", K::value_tag(), V::value_tag())
+    }
+}
+
+impl<A: ValueTag, B: ValueTag> ValueTag for (A, B) {
+    fn value_tag() -> String {
+        format!("({},{})", A::value_tag(), B::value_tag())
+    }
+}
+
+impl<A: ValueTag, B: ValueTag, C: ValueTag> ValueTag for (A, B, C) {
+    fn value_tag() -> String {
+        format!("({},{},{})", A::value_tag(), B::value_tag(), C::value_tag())
+    }
+}
+
+// the tag written into the header for values of type T
+pub fn value_type_tag<T: ValueTag>() -> String {
+    T::value_tag()
+}
+
+pub fn write_header(buffer: &mut Vec<u8>, kind: FileKind, value_type: &str) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&MAGIC);
+    buffer.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buffer.push(kind as u8);
+    buffer.extend_from_slice(&(value_type.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(value_type.as_bytes());
+    let checksum = crc32fast::hash(&buffer[start..]);
+    buffer.extend_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn read_header(data: &[u8], kind: FileKind, value_type: &str) -> Result<usize, FormatError> {
+    // check the header against the expected kind and value type and return where the first record starts
+    let truncated = |offset| FormatError::Truncated { what: "file header".to_string(), offset };
+    let fixed = data.get(..HEADER_FIXED_LEN).ok_or(truncated(data.len()))?;
+    if fixed[..4] != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+    let version = u16::from_le_bytes([fixed[4], fixed[5]]);
+    if version != FORMAT_VERSION {
+        return Err(FormatError::UnsupportedVersion { what: "container format".to_string(), version: version as u32 });
+    }
+    let tag_len = u16::from_le_bytes([fixed[7], fixed[8]]) as usize;
+    let tag_end = HEADER_FIXED_LEN + tag_len;
+    let tag = data.get(HEADER_FIXED_LEN..tag_end).ok_or(truncated(data.len()))?;
+    let checksum = data.get(tag_end..tag_end + 4).ok_or(truncated(data.len()))?;
+    if crc32fast::hash(&data[..tag_end]).to_le_bytes() != checksum {
+        return Err(FormatError::ChecksumMismatch { what: "file header".to_string(), offset: 0 });
+    }
+
+    if fixed[6] != kind as u8 {
+        return Err(FormatError::WrongKind { expected: kind, found: fixed[6] });
+    }
+    let tag = String::from_utf8_lossy(tag);
+    if tag != value_type {
+        return Err(FormatError::TypeMismatch { expected: value_type.to_string(), found: tag.into_owned() });
+    }
+    Ok(tag_end + 4)
+}
+
+fn record_len(len: usize) -> Result<u32, FormatError> {
+    u32::try_from(len).map_err(|_| FormatError::TooLarge { len })
+}
+
+pub fn write_record(buffer: &mut Vec<u8>, payload: &[u8]) -> Result<(), FormatError> {
+    buffer.extend_from_slice(&record_len(payload.len())?.to_le_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buffer.extend_from_slice(payload);
+    Ok(())
+}
+
+pub fn read_record<'a>(data: &'a [u8], offset: usize, what: &str) -> Result<(&'a [u8], usize), FormatError> {
+    // returns the payload of the record at the offset and the offset of the record after it
+    let truncated = || FormatError::Truncated { what: what.to_string(), offset };
+    let header = data.get(offset..offset.checked_add(RECORD_HEADER_LEN).ok_or_else(truncated)?).ok_or_else(truncated)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let start = offset + RECORD_HEADER_LEN;
+    let payload = data.get(start..start.checked_add(len).ok_or_else(truncated)?).ok_or_else(truncated)?;
+    if crc32fast::hash(payload) != checksum {
+        return Err(FormatError::ChecksumMismatch { what: what.to_string(), offset });
+    }
+    Ok((payload, start + len))
+}
+
+pub fn record_end(data: &[u8], offset: usize) -> Option<usize> {
+    // where the record at the offset says it ends, without checking that the bytes are there or intact
+    let header = data.get(offset..offset.checked_add(RECORD_HEADER_LEN)?)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    (offset + RECORD_HEADER_LEN).checked_add(len)
+}
+
+pub fn encode<T: Serialize + ?Sized>(value: &T, what: &str) -> crate::error::Result<Vec<u8>> {
+    // bincode can refuse a value, for example a map flattened into a struct, so that is an error and not a panic
+    serialize(value).map_err(|e| MapRootError::Encode { what: what.to_string(), message: e.to_string() })
+}
+
+pub fn decode<T: DeserializeOwned>(payload: &[u8], what: &str) -> Result<T, FormatError> {
+    deserialize(payload).map_err(|e| FormatError::Decode { what: what.to_string(), message: e.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(kind: FileKind, tag: &str, records: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_header(&mut data, kind, tag);
+        for record in records {
+            write_record(&mut data, record).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn records_read_back_in_order() {
+        let data = file(FileKind::Structure, "u32", &[b"first", b"", b"third"]);
+        let mut offset = read_header(&data, FileKind::Structure, "u32").unwrap();
+        let mut payloads = Vec::new();
+        while offset < data.len() {
+            let (payload, next) = read_record(&data, offset, "record").unwrap();
+            payloads.push(payload.to_vec());
+            offset = next;
+        }
+        assert_eq!(payloads, vec![b"first".to_vec(), Vec::new(), b"third".to_vec()]);
+    }
+
+    #[test]
+    fn wrong_magic_is_rejected() {
+        let mut data = file(FileKind::Database, "u32", &[]);
+        data[0] = b'X';
+        assert_eq!(read_header(&data, FileKind::Database, "u32"), Err(FormatError::BadMagic));
+        assert_eq!(read_header(b"not a database at all", FileKind::Database, "u32"), Err(FormatError::BadMagic));
+    }
+
+    #[test]
+    fn wrong_kind_and_type_are_rejected() {
+        let data = file(FileKind::Log, "u32", &[]);
+        assert_eq!(read_header(&data, FileKind::Database, "u32"), Err(FormatError::WrongKind { expected: FileKind::Database, found: FileKind::Log as u8 }));
+        assert_eq!(
+            read_header(&data, FileKind::Log, "string"),
+            Err(FormatError::TypeMismatch { expected: "string".to_string(), found: "u32".to_string() })
+        );
+    }
+
+    #[test]
+    fn cut_off_file_is_truncated_not_a_panic() {
+        let data = file(FileKind::Structure, "u32", &[b"payload"]);
+        let header_len = read_header(&data, FileKind::Structure, "u32").unwrap();
+        for cut in 0..header_len {
+            assert!(matches!(read_header(&data[..cut], FileKind::Structure, "u32"), Err(FormatError::Truncated { .. })), "cut at {}", cut);
+        }
+        for cut in header_len..data.len() {
+            assert_eq!(
+                read_record(&data[..cut], header_len, "record"),
+                Err(FormatError::Truncated { what: "record".to_string(), offset: header_len })
+            );
+        }
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        let data = file(FileKind::Structure, "u32", &[b"payload"]);
+        let header_len = read_header(&data, FileKind::Structure, "u32").unwrap();
+        // a flipped tag byte, the magic and kind are covered by their own checks
+        let mut damaged = data.clone();
+        damaged[HEADER_FIXED_LEN] ^= 0x01;
+        assert_eq!(
+            read_header(&damaged, FileKind::Structure, "u32"),
+            Err(FormatError::ChecksumMismatch { what: "file header".to_string(), offset: 0 })
+        );
+        let mut damaged = data.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0x01;
+        assert_eq!(
+            read_record(&damaged, header_len, "record"),
+            Err(FormatError::ChecksumMismatch { what: "record".to_string(), offset: header_len })
+        );
+    }
+
+    #[test]
+    fn garbage_payload_is_a_decode_error() {
+        assert!(matches!(decode::<String>(&[0xff], "value"), Err(FormatError::Decode { .. })));
+    }
+
+    #[test]
+    fn payload_too_long_for_the_length_field_is_refused() {
+        assert_eq!(record_len(u32::MAX as usize), Ok(u32::MAX));
+        if usize::BITS > 32 {
+            let len = u32::MAX as usize + 1;
+            assert_eq!(record_len(len), Err(FormatError::TooLarge { len }));
+        }
+    }
+}
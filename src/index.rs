@@ -0,0 +1,295 @@
+// lookups into a structure by something other than the node key
+// an index is built from a function over the node value and kept up to date by the structure on every change,
+// the functions are not saved with the structure so they have to be registered again after loading it
+
+use crate::node::{IdMap, NodeId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::RangeBounds;
+
+// produces the alternate key of a value, None leaves the node out of the index
+pub type AltKeyExtractor<T> = Box<dyn Fn(&T) -> Option<String> + Send + Sync>;
+
+// alternate keys are unique, each one maps to a single node the same way the node key does
+pub(crate) struct AltKeyIndex<T> {
+    extractor: AltKeyExtractor<T>,
+    nodes: HashMap<String, NodeId>, // alternate key to node
+    keys: IdMap<String>,            // node to its alternate key, so it can be dropped without running the extractor again
+}
+
+impl<T> AltKeyIndex<T> {
+    pub(crate) fn new(extractor: AltKeyExtractor<T>) -> Self {
+        AltKeyIndex { extractor, nodes: HashMap::new(), keys: IdMap::default() }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<NodeId> {
+        self.nodes.get(key).copied()
+    }
+
+    pub(crate) fn conflict(&self, value: &T, id: Option<NodeId>) -> Option<String> {
+        // the alternate key the value would get if another node already holds it
+        let key = (self.extractor)(value)?;
+        match self.nodes.get(&key) {
+            Some(holder) if Some(*holder) != id => Some(key),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, id: NodeId, value: &T) {
+        // callers check conflict first, the node is moved to the key its value produces now
+        self.remove(id);
+        if let Some(key) = (self.extractor)(value) {
+            self.nodes.insert(key.clone(), id);
+            self.keys.insert(id, key);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: NodeId) {
+        if let Some(key) = self.keys.remove(&id) {
+            self.nodes.remove(&key);
+        }
+    }
+}
+
+// the value a secondary index orders nodes by
+// values of different kinds sort by kind first, in the order the variants are listed, and never compare equal
+// a tuple orders by its first element, then its second and so on, which makes composite indexes
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IndexValue {
+    Int(i64),
+    UInt(u64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<IndexValue>),
+}
+
+impl IndexValue {
+    pub fn starts_with(&self, prefix: &IndexValue) -> bool {
+        // text, bytes and tuples have prefixes of their own kind, a number is only a prefix of itself
+        match (self, prefix) {
+            (IndexValue::Text(value), IndexValue::Text(prefix)) => value.starts_with(prefix.as_str()),
+            (IndexValue::Bytes(value), IndexValue::Bytes(prefix)) => value.starts_with(prefix),
+            (IndexValue::Tuple(value), IndexValue::Tuple(prefix)) => value.starts_with(prefix),
+            (value, prefix) => value == prefix,
+        }
+    }
+}
+
+impl fmt::Display for IndexValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexValue::Int(n) => write!(f, "{}", n),
+            IndexValue::UInt(n) => write!(f, "{}", n),
+            IndexValue::Text(s) => write!(f, "{}", s),
+            IndexValue::Bytes(bytes) => bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+            IndexValue::Tuple(values) => {
+                write!(f, "(")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl From<i64> for IndexValue {
+    fn from(n: i64) -> Self {
+        IndexValue::Int(n)
+    }
+}
+
+impl From<u64> for IndexValue {
+    fn from(n: u64) -> Self {
+        IndexValue::UInt(n)
+    }
+}
+
+impl From<String> for IndexValue {
+    fn from(s: String) -> Self {
+        IndexValue::Text(s)
+    }
+}
+
+impl From<&str> for IndexValue {
+    fn from(s: &str) -> Self {
+        IndexValue::Text(s.to_string())
+    }
+}
+
+impl From<Vec<u8>> for IndexValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        IndexValue::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for IndexValue {
+    fn from(bytes: &[u8]) -> Self {
+        IndexValue::Bytes(bytes.to_vec())
+    }
+}
+
+impl<A: Into<IndexValue>, B: Into<IndexValue>> From<(A, B)> for IndexValue {
+    fn from((a, b): (A, B)) -> Self {
+        IndexValue::Tuple(vec![a.into(), b.into()])
+    }
+}
+
+impl<A: Into<IndexValue>, B: Into<IndexValue>, C: Into<IndexValue>> From<(A, B, C)> for IndexValue {
+    fn from((a, b, c): (A, B, C)) -> Self {
+        IndexValue::Tuple(vec![a.into(), b.into(), c.into()])
+    }
+}
+
+// produces the indexed value of a node value, None leaves the node out of the index
+pub type IndexExtractor<T> = Box<dyn Fn(&T) -> Option<IndexValue> + Send + Sync>;
+
+// an ordered index, several nodes can share a value unless the index is unique
+pub(crate) struct SecondaryIndex<T> {
+    extractor: IndexExtractor<T>,
+    unique: bool,
+    nodes: BTreeMap<IndexValue, BTreeSet<NodeId>>, // indexed value to the nodes holding it, in id order so results are stable
+    values: IdMap<IndexValue>,                      // node to its indexed value
+}
+
+impl<T> SecondaryIndex<T> {
+    pub(crate) fn new(extractor: IndexExtractor<T>, unique: bool) -> Self {
+        SecondaryIndex { extractor, unique, nodes: BTreeMap::new(), values: IdMap::default() }
+    }
+
+    pub(crate) fn conflict(&self, value: &T, id: Option<NodeId>) -> Option<IndexValue> {
+        // only a unique index can refuse a value, and only when a different node already holds it
+        if !self.unique {
+            return None;
+        }
+        let key = (self.extractor)(value)?;
+        let holders = self.nodes.get(&key)?;
+        if holders.iter().any(|holder| Some(*holder) != id) {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn insert(&mut self, id: NodeId, value: &T) {
+        self.remove(id);
+        if let Some(key) = (self.extractor)(value) {
+            self.nodes.entry(key.clone()).or_default().insert(id);
+            self.values.insert(id, key);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: NodeId) {
+        if let Some(key) = self.values.remove(&id) {
+            if let Some(holders) = self.nodes.get_mut(&key) {
+                holders.remove(&id);
+                if holders.is_empty() {
+                    self.nodes.remove(&key);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn range<R: RangeBounds<IndexValue>>(&self, range: R) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes.range(range).flat_map(|(_, holders)| holders.iter().copied())
+    }
+
+    pub(crate) fn prefix(&self, prefix: IndexValue) -> impl Iterator<Item = NodeId> + '_ {
+        // values of one kind sort together, so everything with the prefix sits right after the prefix itself
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .flat_map(|(_, holders)| holders.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexValue;
+    use crate::error::MapRootError;
+    use crate::node::{NewNode, Node};
+    use crate::policy::UnStrict;
+    use crate::structure::Structure;
+
+    // a city and a population per node
+    fn cities() -> Structure<(String, u64)> {
+        let mut structure = Structure::new(None, UnStrict);
+        for (key, city, population) in [("n1", "berlin", 3_700_000), ("n2", "bern", 134_000), ("n3", "bonn", 330_000), ("n4", "basel", 173_000)] {
+            structure.add_node(NewNode::new(key.to_string(), (city.to_string(), population))).unwrap();
+        }
+        structure
+    }
+
+    fn keys(nodes: Vec<&Node<(String, u64)>>) -> Vec<&str> {
+        nodes.into_iter().map(|node| node.key.as_str()).collect()
+    }
+
+    #[test]
+    fn alt_keys_follow_every_change_and_stay_unique() {
+        let mut structure = cities();
+        // the smallest cities are left out of the index
+        let city = |(city, population): &(String, u64)| (*population > 150_000).then(|| city.clone());
+        structure.register_alt_key("city", city).unwrap();
+        assert_eq!(structure.find_node_by_alt_key("city", "bonn").unwrap().unwrap().key, "n3");
+        assert!(structure.find_node_by_alt_key("city", "bern").unwrap().is_none());
+        assert!(matches!(structure.register_alt_key("city", city), Err(MapRootError::DuplicateKey(_))));
+
+        assert!(matches!(structure.add_node(NewNode::new("n5".to_string(), ("bonn".to_string(), 200_000))), Err(MapRootError::DuplicateKey(key)) if key == "city:bonn"));
+        assert!(matches!(structure.edit_value("n4", ("berlin".to_string(), 200_000)), Err(MapRootError::DuplicateKey(_))));
+        // below the cut off the value produces no key, so it can not clash
+        structure.add_node(NewNode::new("n5".to_string(), ("bonn".to_string(), 1))).unwrap();
+
+        structure.edit_value("n3", ("koeln".to_string(), 1_000_000)).unwrap();
+        assert!(structure.find_node_by_alt_key("city", "bonn").unwrap().is_none());
+        assert_eq!(structure.find_node_by_alt_key("city", "koeln").unwrap().unwrap().key, "n3");
+        structure.delete_node_by_key("n3").unwrap();
+        assert!(structure.find_node_by_alt_key("city", "koeln").unwrap().is_none());
+
+        // a second index over values that repeat can not be registered
+        assert!(matches!(structure.register_alt_key("upper", |(city, _): &(String, u64)| Some(city[..1].to_string())), Err(MapRootError::DuplicateKey(_))));
+        assert!(matches!(structure.find_node_by_alt_key("upper", "b"), Err(MapRootError::UnknownIndex(_))));
+        structure.remove_alt_key("city").unwrap();
+        assert!(matches!(structure.find_node_by_alt_key("city", "berlin"), Err(MapRootError::UnknownIndex(_))));
+    }
+
+    #[test]
+    fn unique_index_refuses_every_change_that_would_share_a_value() {
+        let mut structure = cities();
+        structure.register_index("city", true, |(city, _): &(String, u64)| Some(city.as_str().into())).unwrap();
+
+        let duplicate = NewNode::new("n5".to_string(), ("bern".to_string(), 1));
+        assert!(matches!(structure.add_node(duplicate), Err(MapRootError::DuplicateKey(key)) if key == "city:bern"));
+        assert!(matches!(structure.edit_value("n3", ("basel".to_string(), 1)), Err(MapRootError::DuplicateKey(_))));
+        assert!(matches!(structure.upsert(NewNode::new("n1".to_string(), ("bonn".to_string(), 1))), Err(MapRootError::DuplicateKey(_))));
+        // a node keeping its own value is not a conflict with itself
+        structure.edit_value("n2", ("bern".to_string(), 135_000)).unwrap();
+        assert_eq!(keys(structure.find_by_index("city", &"bern".into()).unwrap()), ["n2"]);
+
+        // registering a unique index over values that already repeat fails and leaves nothing behind
+        structure.edit_value("n3", ("bonn".to_string(), 135_000)).unwrap();
+        let by_population = |(_, population): &(String, u64)| Some(IndexValue::UInt(*population));
+        assert!(matches!(structure.register_index("population", true, by_population), Err(MapRootError::DuplicateKey(_))));
+        assert!(matches!(structure.find_by_index("population", &0u64.into()), Err(MapRootError::UnknownIndex(_))));
+    }
+
+    #[test]
+    fn range_and_prefix_follow_the_value_order() {
+        let mut structure = cities();
+        structure.register_index("population", false, |(_, population): &(String, u64)| Some((*population).into())).unwrap();
+        structure.register_index("city", false, |(city, _): &(String, u64)| Some(city.as_str().into())).unwrap();
+        structure.register_index("initial", false, |(city, population): &(String, u64)| Some((&city[..1], *population).into())).unwrap();
+        structure.register_index("raw", false, |(city, _): &(String, u64)| Some(city.as_bytes().into())).unwrap();
+
+        assert_eq!(keys(structure.index_range("population", IndexValue::UInt(150_000)..IndexValue::UInt(400_000)).unwrap()), ["n4", "n3"]);
+        assert_eq!(keys(structure.index_range("population", ..=IndexValue::UInt(173_000)).unwrap()), ["n2", "n4"]);
+        assert_eq!(keys(structure.index_prefix("city", "ber").unwrap()), ["n1", "n2"]);
+        assert_eq!(keys(structure.index_prefix("raw", &b"b"[..]).unwrap()), ["n4", "n1", "n2", "n3"]);
+        // a composite index answers a prefix of its leading elements, ordered by the rest
+        assert_eq!(keys(structure.index_prefix("initial", IndexValue::Tuple(vec!["b".into()])).unwrap()), ["n2", "n4", "n3", "n1"]);
+        assert!(structure.index_prefix("city", "z").unwrap().is_empty());
+        assert!(matches!(structure.index_prefix("missing", "b"), Err(MapRootError::UnknownIndex(_))));
+    }
+}
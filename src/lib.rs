@@ -2,7 +2,23 @@ mod node;
 mod structure;
 mod database; 
 mod puppet; 
+mod wal; 
+mod format; 
+mod error; 
+mod policy; 
+mod index; 
+mod traverse; 
+mod reach; 
+mod shared; 
 
 
-pub use node::Node;
-pub use structure::Structure;
\ No newline at end of file
+pub use node::{Edge, NewNode, Node, NodeId};
+pub use structure::{KeyPage, Structure, StructureHeader, STRUCTURE_FORMAT_VERSION};
+pub use database::{wal_path, CatalogEntry, CheckpointPolicy, PrimInitDatabase, PrimInitStructureWrapper, DATABASE_FORMAT_VERSION};
+pub use wal::{WalOp, WriteAheadLog};
+pub use shared::SharedDatabase;
+pub use error::{MapRootError, Result};
+pub use policy::{Connected, Dag, PolicyRegistry, Rooted, SemiStrict, StructurePolicy, Tree, UnStrict};
+pub use index::{AltKeyExtractor, IndexExtractor, IndexValue};
+pub use traverse::{Direction, Order, Walk};
+pub use format::{FileKind, FormatError, ValueTag, FORMAT_VERSION, MAGIC};
@@ -1,187 +1,257 @@
-use std::rc::Rc;
-use std::cell::{RefCell, Ref, RefMut};
-use std::collections::HashSet; 
-use std::hash::{Hash, Hasher}; 
-use bincode::{serialize, deserialize};
-use serde::{Serialize, Deserialize};
-use std::io::Write;
-
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+use crate::format::{decode, encode, FormatError};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+
+// compact handle to a node inside the arena of the structure that owns it
+// an id is only meaningful for the structure it came from and may be reused once its node is deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub(crate) u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
 
 
+// node ids are small dense integers, so they are hashed with a single multiply instead of SipHash
+// and the sets holding them carry no per-set random state
+#[derive(Default, Clone, Copy)]
+pub(crate) struct IdHasher(u64);
 
-#[derive(Clone)]
-pub struct NodeRef<T: Clone>(Rc<RefCell<Node<T>>>);
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
 
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+    }
 
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (self.0 ^ n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
 
+pub(crate) type IdSet = HashSet<NodeId, BuildHasherDefault<IdHasher>>;
+pub(crate) type IdMap<V> = HashMap<NodeId, V, BuildHasherDefault<IdHasher>>;
 
-impl<T: Clone + Serialize> NodeRef<T> {
 
-    pub fn serialize_node(&self) -> Vec<u8> {
-        let node: Ref<'_, Node<T>>= self.borrow(); 
-        // split the node into its components to serialize each 
-        let key: String = node.key.clone();
-        let value: T = node.value.clone();
-        let parents: Vec<String> = node.parents.iter().map(|parent| parent.key()).collect::<Vec<String>>();
-        let children: Vec<String> = node.children.iter().map(|child| child.key()).collect::<Vec<String>>();
+// what a labeled edge says about why two nodes are connected
+// the payload is any serializable value stored as bincode bytes, so edges do not depend on the node value type
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Edge {
+    pub label: String,
+    pub payload: Option<Vec<u8>>,
+}
 
-        // now we serialize each component
-        let key_serialized: Vec<u8> = serialize(&key).unwrap();
-        let value_serialized: Vec<u8> = serialize(&value).unwrap();
-        let parents_serialized: Vec<u8> = serialize(&parents).unwrap();
-        let children_serialized: Vec<u8> = serialize(&children).unwrap();
+impl Edge {
+    pub fn new(label: &str) -> Self {
+        Edge { label: label.to_string(), payload: None }
+    }
 
-        fn write_with_length(buffer: &mut Vec<u8>, data: Vec<u8>) {
-            let len = data.len() as u64; 
-            buffer.extend_from_slice(&len.to_le_bytes());
-            buffer.extend_from_slice(&data); 
+    pub fn with_payload<P: Serialize>(mut self, payload: &P) -> Result<Self> {
+        self.payload = Some(encode(payload, "edge payload")?);
+        Ok(self)
+    }
 
+    pub fn payload<P: DeserializeOwned>(&self) -> Result<Option<P>> {
+        match &self.payload {
+            Some(bytes) => Ok(Some(decode(bytes, "edge payload")?)),
+            None => Ok(None),
         }
+    }
+}
 
-        let mut s_node = Vec::new();    
-
-        write_with_length(&mut s_node, key_serialized); 
-        write_with_length(&mut s_node, value_serialized);   
-        write_with_length(&mut s_node, parents_serialized);
-        write_with_length(&mut s_node, children_serialized);
-
-        s_node
 
-        
-    } 
+// a node as stored in a structure, edges point at the ids of other nodes in the same structure
+// an edge to a child can carry an Edge, plain edges are only in the id sets
+pub struct Node<T: Clone> {
+    pub key: String,
+    pub value: T,
+    pub(crate) parents: IdSet,
+    pub(crate) children: IdSet,
+    pub(crate) edges: IdMap<Edge>, // labels of the edges to children, by child id
+}
 
-    pub fn new(key: String, value: T) -> NodeRef<T> {
-        NodeRef(Rc::new(RefCell::new(Node {
-            key,
-            value,
-            parents: HashSet::new(),
-            children: HashSet::new(),
-        })))
+impl<T: Clone> Node<T> {
+    pub(crate) fn new(key: String, value: T) -> Self {
+        Node { key, value, parents: IdSet::default(), children: IdSet::default(), edges: IdMap::default() }
     }
 
-    pub fn key(&self) -> String {
-        self.0.borrow().key.clone()
+    // the id sets stay inside the crate so the hasher behind them can change, these only hand out ids
+    pub fn parent_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.parents.iter().copied()
     }
-    
-    pub fn rc_clone(&self) -> NodeRef<T> {
-        let rc: Rc<RefCell<Node<T>>> = Rc::clone(&self.0);
-        NodeRef(rc)
+
+    pub fn child_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.children.iter().copied()
     }
 
-    pub fn borrow(&self) -> Ref<'_, Node<T>> {
-        self.0.borrow()
+    pub fn labeled_edges(&self) -> impl Iterator<Item = (NodeId, &Edge)> {
+        self.edges.iter().map(|(child, edge)| (*child, edge))
     }
 
-    pub fn borrow_mut(&self) -> RefMut<'_, Node<T>> {
-        self.0.borrow_mut()
+    pub fn edge_to(&self, child: NodeId) -> Option<&Edge> {
+        self.edges.get(&child)
     }
 
-    pub fn value(&self) -> T {
-        self.0.borrow().value.clone()
-    }    
+    pub fn has_parent(&self, id: NodeId) -> bool {
+        self.parents.contains(&id)
+    }
 
-    pub fn add_parent(&mut self, parent: NodeRef<T>) {
-        // Add the parent to the current node's parent set
-        {
-            let mut node_self: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&self.0); 
-            node_self.parents.insert(parent.rc_clone());
-        }
-        {
-            let mut node_parent: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&parent.0);
-            node_parent.children.insert(self.rc_clone()); 
-        }
+    pub fn has_child(&self, id: NodeId) -> bool {
+        self.children.contains(&id)
     }
+}
 
-    pub fn add_child(&mut self, child: NodeRef<T>) {
-        {
-            let mut node_self: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&self.0);
-            node_self.children.insert(child.rc_clone());
-        } // `node_self` is dropped here
-    
-        {
-            let mut node_child: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&child.0); 
-            node_child.parents.insert(self.rc_clone());
-        }
 
-    }
+// a node that is not part of a structure yet
+// its parents and children are the keys of nodes it should be linked to once it is added
+// this is also what a serialized node record decodes back into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewNode<T: Clone> {
+    pub key: String,
+    pub value: T,
+    pub parents: Vec<String>,
+    pub children: Vec<String>,
+    pub edges: Vec<(String, Edge)>, // labels for some of the children, by child key
+}
 
-    pub fn parents(&self) -> Ref<'_, HashSet<NodeRef<T>>> {
-        Ref::map(self.0.borrow(), |node| &node.parents)
+impl<T: Clone> NewNode<T> {
+    pub fn new(key: String, value: T) -> Self {
+        NewNode { key, value, parents: Vec::new(), children: Vec::new(), edges: Vec::new() }
     }
 
-    // Returns an immutable reference to the set of children
-    pub fn children(&self) -> Ref<'_, HashSet<NodeRef<T>>> {
-        Ref::map(self.0.borrow(), |node| &node.children)
-    } 
-
-    pub fn has_parent_by_key(&self, key: &str) -> bool {
-        self.parents().iter().any(|parent| parent.key() == key)
+    pub fn add_labeled_child(&mut self, key: &str, edge: Edge) {
+        self.add_child(key);
+        self.edges.retain(|(child, _)| child != key);
+        self.edges.push((key.to_string(), edge));
     }
 
-    pub fn has_child_by_key(&self, key: &str) -> bool {
-        self.children().iter().any(|child| child.key() == key)
+    pub fn add_parent(&mut self, key: &str) {
+        if !self.parents.iter().any(|parent| parent == key) {
+            self.parents.push(key.to_string());
+        }
     }
 
-    pub fn get_parent_by_key(&self, key: &str) -> Option<NodeRef<T>> {
-        self.parents().iter().find(|parent| parent.key() == key).map(|parent| parent.rc_clone())
+    pub fn add_child(&mut self, key: &str) {
+        if !self.children.iter().any(|child| child == key) {
+            self.children.push(key.to_string());
+        }
     }
+}
 
-    pub fn get_child_by_key(&self, key: &str) -> Option<NodeRef<T>> {
-        self.children().iter().find(|child| child.key() == key).map(|child| child.rc_clone())
+impl<T: Clone + Serialize> NewNode<T> {
+    pub fn serialize_node(&self) -> Result<Vec<u8>> {
+        serialize_node(&self.key, &self.value, &self.parents, &self.children, &self.edges)
     }
+}
+
+impl<T: Clone + DeserializeOwned> NewNode<T> {
+    pub fn deserialize_node(data: &[u8]) -> Result<NewNode<T>> {
+        // the inverse of serialize_node, gives back the node with the keys of its parents and children
+        // so the caller can relink them
+        fn read_with_length<'a>(data: &'a [u8], offset: &mut usize, what: &str) -> std::result::Result<&'a [u8], FormatError> {
+            let truncated = || FormatError::Truncated { what: format!("node record {}", what), offset: *offset };
+            let len_bytes: [u8; 8] = data.get(*offset..*offset + 8).ok_or_else(truncated)?.try_into().unwrap();
+            let len = u64::from_le_bytes(len_bytes) as usize;
+            let start = *offset + 8;
+            let end = start.checked_add(len).ok_or_else(truncated)?;
+            let part = data.get(start..end).ok_or_else(truncated)?;
+            *offset = end;
+            Ok(part)
+        }
 
-    pub fn edit_value(&mut self, value: T) {
-        let mut node: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&self.0);
-        node.value = value;
+        let mut offset = 0;
+        let key: String = decode(read_with_length(data, &mut offset, "key")?, "node key")?;
+        let value: T = decode(read_with_length(data, &mut offset, "value")?, "node value")?;
+        let parents: Vec<String> = decode(read_with_length(data, &mut offset, "parents")?, "node parents")?;
+        let children: Vec<String> = decode(read_with_length(data, &mut offset, "children")?, "node children")?;
+        let edges: Vec<(String, Edge)> = decode(read_with_length(data, &mut offset, "edges")?, "node edges")?;
+
+        Ok(NewNode { key, value, parents, children, edges })
     }
+}
 
-    pub fn delete_node(&mut self) {
-        // remove the node from the given sets of all its parents and children
-        // perma delete the node after this
-        let mut node: std::cell::RefMut<'_, Node<T>> = RefCell::borrow_mut(&self.0);
-        
 
-        // remove the node from all its parents
-        for parent in node.parents.iter() {
-            let mut parent_node: RefMut<'_, Node<T>> = RefCell::borrow_mut(&parent.0);
-            parent_node.children.remove(&self.rc_clone());
-        }
+pub(crate) fn serialize_node<T: Serialize, K: Serialize>(key: &str, value: &T, parents: &[K], children: &[K], edges: &[(K, Edge)]) -> Result<Vec<u8>> {
+    // a node record is the key, value, parent keys, child keys and the labeled edges to children by child key,
+    // each serialized with bincode and prefixed with its length as a little endian u64
+    let key_serialized: Vec<u8> = encode(key, "node key")?;
+    let value_serialized: Vec<u8> = encode(value, "node value")?;
+    let parents_serialized: Vec<u8> = encode(parents, "node parents")?;
+    let children_serialized: Vec<u8> = encode(children, "node children")?;
+    let edges_serialized: Vec<u8> = encode(edges, "node edges")?;
 
-        // remove the node from all its children
-        for child in node.children.iter() {
-            let mut child_node: RefMut<'_, Node<T>> = RefCell::borrow_mut(&child.0); 
-            child_node.parents.remove(&self.rc_clone());    
-        }
+    fn write_with_length(buffer: &mut Vec<u8>, data: Vec<u8>) {
+        let len = data.len() as u64;
+        buffer.extend_from_slice(&len.to_le_bytes());
+        buffer.extend_from_slice(&data);
 
-        // delete the node
-        drop(node);
     }
 
+    let mut s_node = Vec::new();
+
+    write_with_length(&mut s_node, key_serialized);
+    write_with_length(&mut s_node, value_serialized);
+    write_with_length(&mut s_node, parents_serialized);
+    write_with_length(&mut s_node, children_serialized);
+    write_with_length(&mut s_node, edges_serialized);
+
+    Ok(s_node)
 }
 
-impl<T: Clone> Hash for NodeRef<T> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.borrow_mut().key.hash(state);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MapRootError;
+    use crate::format::ValueTag;
+    use crate::policy::UnStrict;
+    use crate::structure::Structure;
+    use std::collections::BTreeMap;
+
+    // bincode can not write a flattened map since it needs the length of every map up front
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Flattened {
+        name: String,
+        #[serde(flatten)]
+        extra: BTreeMap<String, String>,
     }
-}
 
-impl<T: Clone> PartialEq for NodeRef<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.borrow_mut().key == other.0.borrow().key
+    impl ValueTag for Flattened {
+        fn value_tag() -> String {
+            "flattened".to_string()
+        }
     }
-}
 
-impl<T: Clone> Eq for NodeRef<T> {}
-pub struct Node<T: Clone> {
-    pub key: String,
-    pub value: T,
-    pub parents: HashSet<NodeRef<T>>,
-    pub children: HashSet<NodeRef<T>>,
-}
+    #[test]
+    fn value_bincode_refuses_is_an_error_not_a_panic() {
+        let value = Flattened { name: "a".to_string(), extra: BTreeMap::from([("k".to_string(), "v".to_string())]) };
+        let node = NewNode::new("a".to_string(), value);
+        assert!(matches!(node.serialize_node(), Err(MapRootError::Encode { .. })));
+
+        let mut structure = Structure::new(None, UnStrict);
+        structure.add_node(node).unwrap();
+        assert!(matches!(structure.serialize_node("a"), Err(MapRootError::Encode { .. })));
+        assert!(matches!(structure.serialize_node("b"), Err(MapRootError::NotFound(_))));
+        assert!(structure.serialize_related_nodes().is_err());
+        assert!(structure.serialize_snapshot().is_err());
+    }
+
+    #[test]
+    fn edge_payload_round_trips_and_refusals_are_errors() {
+        let edge = Edge::new("owns").with_payload(&(7u32, "seven".to_string())).unwrap();
+        assert_eq!(edge.payload::<(u32, String)>().unwrap(), Some((7, "seven".to_string())));
+        assert_eq!(Edge::new("owns").payload::<u32>().unwrap(), None);
 
-impl<T: Clone + Serialize> Node<T> {
-    pub fn new(key: String, value: T) -> NodeRef<T> {
-        NodeRef::new(key, value)
+        let value = Flattened { name: "a".to_string(), extra: BTreeMap::new() };
+        assert!(matches!(Edge::new("owns").with_payload(&value), Err(MapRootError::Encode { .. })));
     }
 }
-
@@ -0,0 +1,676 @@
+// the integrity rules a structure enforces on every change
+// a structure asks its policy before a node is added or removed and before an edge is created or dropped,
+// an error from the policy refuses the change and is handed back to the caller untouched
+// the name of the policy is saved with the structure so a snapshot can find the same rules again when it is loaded
+//
+// the built in policies
+//     un-strict    anything goes, nodes can be added without any parents or children
+//     semi-strict  every node needs at least one parent or child in the structure, unless it is the first node
+//     dag          like un-strict but no cycle may ever be formed through child edges
+//     tree         every node except the root has exactly one parent, the root is the first node and has none
+//     rooted       every node can be reached from the root through child edges, the root is the first node
+//     connected    the structure stays in one piece when edge directions are ignored, unlike semi-strict
+//                  which only makes sure every node keeps a neighbour
+
+use crate::error::{MapRootError, Result};
+use crate::node::{IdMap, IdSet, NewNode, NodeId};
+use crate::structure::Structure;
+use std::collections::{HashMap, VecDeque};
+
+pub trait StructurePolicy<T: Clone>: Send + Sync {
+    // the name written into snapshots, it has to be unique within a PolicyRegistry
+    fn name(&self) -> &str;
+
+    // the node has already been checked to only name existing neighbours that are not itself
+    fn before_add(&self, _structure: &Structure<T>, _node: &NewNode<T>) -> Result<()> {
+        Ok(())
+    }
+
+    fn before_remove(&self, _structure: &Structure<T>, _id: NodeId) -> Result<()> {
+        Ok(())
+    }
+
+    fn before_link(&self, _structure: &Structure<T>, _parent: NodeId, _child: NodeId) -> Result<()> {
+        Ok(())
+    }
+
+    fn before_unlink(&self, _structure: &Structure<T>, _parent: NodeId, _child: NodeId) -> Result<()> {
+        Ok(())
+    }
+
+    // the child trades its from -> child edge for a to -> child edge as one change, from is a parent of the child
+    // and to is neither from nor the child, by default both halves have to pass on their own, policies where
+    // the unlink alone would break a rule the move as a whole keeps override it
+    fn before_move(&self, structure: &Structure<T>, child: NodeId, from: NodeId, to: NodeId) -> Result<()> {
+        self.before_unlink(structure, from, child)?;
+        self.before_link(structure, to, child)
+    }
+
+    // None clears the root
+    fn before_set_root(&self, _structure: &Structure<T>, _root: Option<NodeId>) -> Result<()> {
+        Ok(())
+    }
+
+    // whether the first node added to an empty structure without a root becomes its root
+    fn roots_first_node(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnStrict;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SemiStrict;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Dag;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tree;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rooted;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Connected;
+
+impl<T: Clone> StructurePolicy<T> for UnStrict {
+    fn name(&self) -> &str {
+        "un-strict"
+    }
+}
+
+impl SemiStrict {
+    fn connected_without<T: Clone>(structure: &Structure<T>, id: NodeId, off_limit: &[NodeId]) -> bool {
+        // whether the node still has a parent or child once the off limit nodes are gone
+        let Some(node) = structure.node(id) else { return false };
+        node.children.iter().chain(node.parents.iter()).any(|neighbour| !off_limit.contains(neighbour))
+    }
+
+    fn connected_without_edge<T: Clone>(structure: &Structure<T>, id: NodeId, parent: NodeId, child: NodeId) -> bool {
+        // whether the node still has a parent or child once the one edge from parent to child is gone,
+        // an edge running the other way between the same two nodes still counts
+        let Some(node) = structure.node(id) else { return false };
+        let children = node.children.iter().filter(|neighbour| !(id == parent && **neighbour == child));
+        let parents = node.parents.iter().filter(|neighbour| !(id == child && **neighbour == parent));
+        children.chain(parents).next().is_some()
+    }
+
+    fn violation<T: Clone>(structure: &Structure<T>, id: NodeId, offending: Vec<NodeId>) -> MapRootError {
+        let key = |id: NodeId| structure.key_of(id).unwrap_or_default().to_string();
+        MapRootError::StrictnessViolation { key: key(id), mode: "semi-strict".to_string(), offending_neighbors: offending.into_iter().map(key).collect() }
+    }
+}
+
+impl<T: Clone> StructurePolicy<T> for SemiStrict {
+    fn name(&self) -> &str {
+        "semi-strict"
+    }
+
+    fn before_add(&self, structure: &Structure<T>, node: &NewNode<T>) -> Result<()> {
+        // only the first node of the structure may come in without a parent or child
+        if node.parents.is_empty() && node.children.is_empty() && structure.has_first_node {
+            return Err(MapRootError::StrictnessViolation { key: node.key.clone(), mode: "semi-strict".to_string(), offending_neighbors: Vec::new() });
+        }
+        Ok(())
+    }
+
+    fn before_remove(&self, structure: &Structure<T>, id: NodeId) -> Result<()> {
+        // every parent and child of the node has to keep at least one other neighbour
+        // unless it is about to be the only node left, which is the same as being the first node
+        // a node without any neighbours can only be the first node, which is always free to go
+        if structure.len() <= 2 {
+            return Ok(());
+        }
+        let Some(node) = structure.node(id) else { return Ok(()) };
+        let offending: Vec<NodeId> = node.parents.iter().chain(node.children.iter())
+            .filter(|neighbour| **neighbour != id && !Self::connected_without(structure, **neighbour, &[id]))
+            .copied()
+            .collect();
+        if offending.is_empty() { Ok(()) } else { Err(Self::violation(structure, id, offending)) }
+    }
+
+    fn before_unlink(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        // both ends of the edge have to keep at least one other neighbour
+        let offending: Vec<NodeId> = [parent, child].into_iter()
+            .filter(|end| !Self::connected_without_edge(structure, *end, parent, child))
+            .collect();
+        if offending.is_empty() { Ok(()) } else { Err(Self::violation(structure, parent, offending)) }
+    }
+
+    fn before_move(&self, structure: &Structure<T>, child: NodeId, from: NodeId, _to: NodeId) -> Result<()> {
+        // the child gets its new parent, so only the old parent has to keep a neighbour
+        if Self::connected_without_edge(structure, from, from, child) { Ok(()) } else { Err(Self::violation(structure, from, vec![from])) }
+    }
+}
+
+impl<T: Clone> StructurePolicy<T> for Dag {
+    fn name(&self) -> &str {
+        "dag"
+    }
+
+    fn before_add(&self, structure: &Structure<T>, node: &NewNode<T>) -> Result<()> {
+        structure.dag_test(node)
+    }
+
+    fn before_link(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        // the edge closes a cycle if the parent can already be reached from the child
+        match structure.child_path(&[child], &[parent].into_iter().collect()) {
+            Some(path) => {
+                let mut cycle = vec![structure.key_of(parent).unwrap_or_default().to_string()];
+                cycle.extend(path.into_iter().map(|id| structure.key_of(id).unwrap_or_default().to_string()));
+                Err(MapRootError::Cycle(cycle))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Tree {
+    fn violation(key: &str, offending_neighbors: Vec<String>) -> MapRootError {
+        MapRootError::StrictnessViolation { key: key.to_string(), mode: "tree".to_string(), offending_neighbors }
+    }
+}
+
+impl<T: Clone> StructurePolicy<T> for Tree {
+    fn name(&self) -> &str {
+        "tree"
+    }
+
+    fn before_add(&self, structure: &Structure<T>, node: &NewNode<T>) -> Result<()> {
+        // the first node is the root and comes in alone, every node after it needs exactly one parent
+        // every node already in the tree has its one parent and the root may not get one,
+        // so a new node can only come in as a leaf
+        let parents_allowed = if structure.has_first_node { 1 } else { 0 };
+        if node.parents.len() != parents_allowed {
+            return Err(Self::violation(&node.key, node.parents.clone()));
+        }
+        if !node.children.is_empty() {
+            return Err(Self::violation(&node.key, node.children.clone()));
+        }
+        Ok(())
+    }
+
+    fn before_remove(&self, structure: &Structure<T>, id: NodeId) -> Result<()> {
+        // the children of the node would be left without a parent, so only leaves can be removed
+        let Some(node) = structure.node(id) else { return Ok(()) };
+        if node.children.is_empty() {
+            return Ok(());
+        }
+        let children = node.children.iter().filter_map(|child| structure.key_of(*child)).map(str::to_string).collect();
+        Err(Self::violation(&node.key, children))
+    }
+
+    fn before_link(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        // the child has to be waiting for a parent, which the root never is
+        let (Some(parent_node), Some(child_node)) = (structure.node(parent), structure.node(child)) else { return Ok(()) };
+        if !child_node.parents.is_empty() || structure.root_id() == Some(child) || parent == child {
+            let mut offending: Vec<String> = child_node.parents.iter().filter_map(|id| structure.key_of(*id)).map(str::to_string).collect();
+            offending.push(parent_node.key.clone());
+            return Err(Self::violation(&child_node.key, offending));
+        }
+        Dag.before_link(structure, parent, child)
+    }
+
+    fn before_unlink(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        // dropping the edge would leave the child without its parent, move_child re-parents a node instead
+        let key = |id| structure.key_of(id).unwrap_or_default().to_string();
+        Err(Self::violation(&key(child), vec![key(parent)]))
+    }
+
+    fn before_move(&self, structure: &Structure<T>, child: NodeId, _from: NodeId, to: NodeId) -> Result<()> {
+        // the child keeps exactly one parent, it only may not end up below itself
+        Dag.before_link(structure, to, child)
+    }
+
+    fn before_set_root(&self, structure: &Structure<T>, root: Option<NodeId>) -> Result<()> {
+        // the root has to be the one node without a parent
+        match root.and_then(|root| structure.node(root)) {
+            Some(node) if !node.parents.is_empty() => {
+                Err(Self::violation(&node.key, node.parents.iter().filter_map(|id| structure.key_of(*id)).map(str::to_string).collect()))
+            }
+            None if !structure.is_empty() => Err(Self::violation(structure.root().map_or("", |root| root.key.as_str()), Vec::new())),
+            _ => Ok(()),
+        }
+    }
+
+    fn roots_first_node(&self) -> bool {
+        true
+    }
+}
+
+impl Rooted {
+    fn unreachable<T: Clone>(structure: &Structure<T>, root: Option<NodeId>, removed: Option<NodeId>, cut: Option<(NodeId, NodeId)>) -> Vec<String> {
+        // walk the child edges from the root as if the removed node and the cut edge were already gone
+        // and return the keys of every other node the walk never reaches
+        let mut reached = IdSet::default();
+        let mut stack: Vec<NodeId> = root.filter(|root| Some(*root) != removed).into_iter().collect();
+        reached.extend(stack.iter().copied());
+        while let Some(current) = stack.pop() {
+            let Some(node) = structure.node(current) else { continue };
+            for child in node.children.iter() {
+                if Some(*child) == removed || cut == Some((current, *child)) {
+                    continue;
+                }
+                if reached.insert(*child) {
+                    stack.push(*child);
+                }
+            }
+        }
+        structure.nodes()
+            .filter(|(id, _)| !reached.contains(id) && Some(*id) != removed)
+            .map(|(_, node)| node.key.clone())
+            .collect()
+    }
+
+    fn check(key: &str, unreachable: Vec<String>) -> Result<()> {
+        if unreachable.is_empty() {
+            return Ok(());
+        }
+        Err(MapRootError::StrictnessViolation { key: key.to_string(), mode: "rooted".to_string(), offending_neighbors: unreachable })
+    }
+}
+
+impl<T: Clone> StructurePolicy<T> for Rooted {
+    fn name(&self) -> &str {
+        "rooted"
+    }
+
+    fn before_add(&self, structure: &Structure<T>, node: &NewNode<T>) -> Result<()> {
+        // the first node becomes the root, every node after it is reachable through any one of its parents
+        if structure.is_empty() || !node.parents.is_empty() {
+            return Ok(());
+        }
+        Self::check(&node.key, vec![node.key.clone()])
+    }
+
+    fn before_remove(&self, structure: &Structure<T>, id: NodeId) -> Result<()> {
+        let key = structure.key_of(id).unwrap_or_default();
+        Self::check(key, Self::unreachable(structure, structure.root_id(), Some(id), None))
+    }
+
+    fn before_unlink(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        let key = structure.key_of(child).unwrap_or_default();
+        Self::check(key, Self::unreachable(structure, structure.root_id(), None, Some((parent, child))))
+    }
+
+    fn before_move(&self, structure: &Structure<T>, child: NodeId, from: NodeId, to: NodeId) -> Result<()> {
+        // whatever the old edge was the only way to is reached again through the new one, as long as
+        // the new parent is not among the nodes that hung off the old edge
+        let key = structure.key_of(child).unwrap_or_default();
+        let unreachable = Self::unreachable(structure, structure.root_id(), None, Some((from, child)));
+        match structure.key_of(to) {
+            Some(to) if !unreachable.iter().any(|key| key == to) => Ok(()),
+            _ => Self::check(key, unreachable),
+        }
+    }
+
+    fn before_set_root(&self, structure: &Structure<T>, root: Option<NodeId>) -> Result<()> {
+        let key = root.and_then(|root| structure.key_of(root)).unwrap_or_default();
+        Self::check(key, Self::unreachable(structure, root, None, None))
+    }
+
+    fn roots_first_node(&self) -> bool {
+        true
+    }
+}
+
+impl Connected {
+    fn cut_off<T: Clone>(structure: &Structure<T>, mut sources: Vec<NodeId>, removed: Option<NodeId>, cut: Option<(NodeId, NodeId)>) -> Vec<NodeId> {
+        // whether the sources can still reach each other once the removed node or the cut edge is gone,
+        // the structure is connected before the change so that is all that has to be checked
+        // instead of walking the whole structure, one breadth first search starts from every source and they take
+        // turns expanding a node each, searches that meet are merged, so when the sources are still connected
+        // only the area between them is visited and when they are not, the search of the smaller island runs out first
+        // returns the nodes of the island that was cut off, empty if every source is still connected
+        sources.sort();
+        sources.dedup();
+        if sources.len() < 2 {
+            return Vec::new();
+        }
+
+        fn find(groups: &mut [usize], mut i: usize) -> usize {
+            while groups[i] != i {
+                groups[i] = groups[groups[i]];
+                i = groups[i];
+            }
+            i
+        }
+
+        let mut owner: IdMap<usize> = IdMap::default();
+        let mut groups: Vec<usize> = (0..sources.len()).collect();
+        // only the first search of every merged group keeps a queue
+        let mut queues: Vec<Option<VecDeque<NodeId>>> = sources.iter().map(|source| Some(VecDeque::from([*source]))).collect();
+        for (i, source) in sources.iter().enumerate() {
+            owner.insert(*source, i);
+        }
+        let mut remaining = sources.len();
+
+        loop {
+            for i in 0..queues.len() {
+                let Some(queue) = queues[i].as_mut() else { continue };
+                let Some(current) = queue.pop_front() else {
+                    // every node this group can reach has been seen and the other groups are not among them
+                    let mut island: Vec<NodeId> = owner.iter().filter(|(_, o)| find(&mut groups, **o) == i).map(|(id, _)| *id).collect();
+                    island.sort();
+                    return island;
+                };
+                let Some(node) = structure.node(current) else { continue };
+                let children = node.children.iter().filter(|child| cut != Some((current, **child)));
+                let parents = node.parents.iter().filter(|parent| cut != Some((**parent, current)));
+                for neighbour in children.chain(parents) {
+                    if Some(*neighbour) == removed {
+                        continue;
+                    }
+                    match owner.get(neighbour) {
+                        None => {
+                            owner.insert(*neighbour, i);
+                            queues[i].as_mut().unwrap().push_back(*neighbour);
+                        }
+                        Some(other) => {
+                            let other = find(&mut groups, *other);
+                            if other != i {
+                                groups[other] = i;
+                                let absorbed = queues[other].take().unwrap_or_default();
+                                queues[i].as_mut().unwrap().extend(absorbed);
+                                remaining -= 1;
+                                if remaining == 1 {
+                                    return Vec::new();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn check<T: Clone>(structure: &Structure<T>, key: &str, island: Vec<NodeId>) -> Result<()> {
+        if island.is_empty() {
+            return Ok(());
+        }
+        let offending_neighbors = island.into_iter().filter_map(|id| structure.key_of(id)).map(str::to_string).collect();
+        Err(MapRootError::StrictnessViolation { key: key.to_string(), mode: "connected".to_string(), offending_neighbors })
+    }
+}
+
+impl<T: Clone> StructurePolicy<T> for Connected {
+    fn name(&self) -> &str {
+        "connected"
+    }
+
+    fn before_add(&self, structure: &Structure<T>, node: &NewNode<T>) -> Result<()> {
+        // any neighbour joins the new node to the one piece the structure is in, only the first node may come alone
+        if structure.is_empty() || !node.parents.is_empty() || !node.children.is_empty() {
+            return Ok(());
+        }
+        Err(MapRootError::StrictnessViolation { key: node.key.clone(), mode: "connected".to_string(), offending_neighbors: vec![node.key.clone()] })
+    }
+
+    fn before_remove(&self, structure: &Structure<T>, id: NodeId) -> Result<()> {
+        // the neighbours of the node have to stay connected to each other without it
+        let Some(node) = structure.node(id) else { return Ok(()) };
+        let neighbours: Vec<NodeId> = node.parents.iter().chain(node.children.iter()).filter(|neighbour| **neighbour != id).copied().collect();
+        Self::check(structure, &node.key, Self::cut_off(structure, neighbours, Some(id), None))
+    }
+
+    fn before_unlink(&self, structure: &Structure<T>, parent: NodeId, child: NodeId) -> Result<()> {
+        let key = structure.key_of(child).unwrap_or_default();
+        Self::check(structure, key, Self::cut_off(structure, vec![parent, child], None, Some((parent, child))))
+    }
+
+    fn before_move(&self, structure: &Structure<T>, child: NodeId, from: NodeId, to: NodeId) -> Result<()> {
+        // dropping the old edge splits the structure in at most two, the new edge joins them again
+        // when it runs between them
+        let key = structure.key_of(child).unwrap_or_default();
+        let island = Self::cut_off(structure, vec![from, child], None, Some((from, child)));
+        if island.contains(&child) != island.contains(&to) {
+            return Ok(());
+        }
+        Self::check(structure, key, island)
+    }
+}
+
+type PolicyFactory<T> = Box<dyn Fn() -> Box<dyn StructurePolicy<T>> + Send + Sync>;
+
+// turns the policy names saved in snapshots back into policies
+// the built in policies are always registered, custom ones are added with register before loading
+pub struct PolicyRegistry<T: Clone> {
+    factories: HashMap<String, PolicyFactory<T>>,
+}
+
+impl<T: Clone + 'static> PolicyRegistry<T> {
+    pub fn new() -> Self {
+        let mut registry = PolicyRegistry { factories: HashMap::new() };
+        registry.register("un-strict", || UnStrict);
+        registry.register("semi-strict", || SemiStrict);
+        registry.register("dag", || Dag);
+        registry.register("tree", || Tree);
+        registry.register("rooted", || Rooted);
+        registry.register("connected", || Connected);
+        registry
+    }
+
+    pub fn register<P, F>(&mut self, name: &str, factory: F)
+    where
+        P: StructurePolicy<T> + 'static,
+        F: Fn() -> P + Send + Sync + 'static,
+    {
+        // a later registration under the same name replaces the earlier one
+        self.factories.insert(name.to_string(), Box::new(move || Box::new(factory())));
+    }
+
+    pub fn create(&self, name: &str) -> Result<Box<dyn StructurePolicy<T>>> {
+        self.factories.get(name).map(|factory| factory()).ok_or_else(|| MapRootError::UnknownMode(name.to_string()))
+    }
+}
+
+impl<T: Clone + 'static> Default for PolicyRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{MapRootError, Result};
+    use crate::node::{Edge, NewNode};
+    use crate::policy::{Connected, Dag, Rooted, SemiStrict, Tree};
+    use crate::structure::Structure;
+
+    fn node(key: &str, parents: &[&str]) -> NewNode<u32> {
+        let mut node = NewNode::new(key.to_string(), 0);
+        for parent in parents {
+            node.add_parent(parent);
+        }
+        node
+    }
+
+    fn offending(result: Result<impl Sized>) -> Vec<String> {
+        match result {
+            Err(MapRootError::StrictnessViolation { offending_neighbors, .. }) => offending_neighbors,
+            Err(e) => panic!("expected a strictness violation, got {}", e),
+            Ok(_) => panic!("expected a strictness violation, the change went through"),
+        }
+    }
+
+    #[test]
+    fn semi_strict_unlink_keeps_the_edge_running_the_other_way() {
+        let mut structure = Structure::new(None, SemiStrict);
+        structure.add_node(node("a", &[])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.link("b", "a").unwrap();
+
+        // b -> a still connects both ends after a -> b is gone
+        structure.unlink("a", "b").unwrap();
+        assert!(matches!(structure.unlink("b", "a"), Err(MapRootError::StrictnessViolation { .. })));
+    }
+
+    #[test]
+    fn tree_moves_a_subtree_but_never_below_itself() {
+        // r -> a -> c -> d, r -> b
+        let mut structure = Structure::new(None, Tree);
+        structure.add_node(node("r", &[])).unwrap();
+        structure.add_node(node("a", &["r"])).unwrap();
+        structure.add_node(node("b", &["r"])).unwrap();
+        structure.add_node(node("c", &["a"])).unwrap();
+        structure.add_node(node("d", &["c"])).unwrap();
+        structure.link_with("a", "c", Edge::new("owns")).unwrap();
+
+        assert!(matches!(structure.unlink("a", "c"), Err(MapRootError::StrictnessViolation { .. })));
+        structure.move_child("c", "a", "b").unwrap();
+        assert!(!structure.has_child_by_key("a", "c"));
+        assert!(structure.has_child_by_key("b", "c"));
+        assert_eq!(structure.edge("b", "c").unwrap().unwrap().label, "owns");
+        assert_eq!(structure.descendants("b").unwrap().map(|node| node.key.as_str()).collect::<Vec<_>>(), vec!["c", "d"]);
+
+        assert!(matches!(structure.move_child("c", "b", "d"), Err(MapRootError::Cycle(_))));
+        assert!(matches!(structure.move_child("c", "b", "c"), Err(MapRootError::Cycle(_))));
+        assert!(matches!(structure.move_child("c", "a", "b"), Err(MapRootError::EdgeNotFound { .. })));
+        assert!(structure.has_child_by_key("b", "c"));
+    }
+
+    #[test]
+    fn rooted_and_connected_move_as_long_as_the_new_parent_stays_on_the_right_side() {
+        // r -> a -> b, r -> c
+        for policy in ["rooted", "connected"] {
+            let mut structure = match policy {
+                "rooted" => Structure::new(None, Rooted),
+                _ => Structure::new(None, Connected),
+            };
+            structure.add_node(node("r", &[])).unwrap();
+            structure.add_node(node("a", &["r"])).unwrap();
+            structure.add_node(node("b", &["a"])).unwrap();
+            structure.add_node(node("c", &["r"])).unwrap();
+
+            // a plain unlink would cut b off, the move hands it to c in the same change
+            assert!(matches!(structure.unlink("a", "b"), Err(MapRootError::StrictnessViolation { .. })), "{}", policy);
+            structure.move_child("b", "a", "c").unwrap();
+            assert!(structure.has_child_by_key("c", "b"));
+
+            // c below its own child b would leave the two of them hanging off each other
+            let Err(MapRootError::StrictnessViolation { offending_neighbors, .. }) = structure.move_child("c", "r", "b") else {
+                panic!("{} moved c below its own child", policy);
+            };
+            // connected names whichever of the two pieces its search ran out on first
+            assert!(offending_neighbors == ["b", "c"] || (policy == "connected" && offending_neighbors == ["r", "a"]), "{} {:?}", policy, offending_neighbors);
+            assert!(structure.has_child_by_key("r", "c"));
+        }
+    }
+
+    #[test]
+    fn semi_strict_move_only_needs_the_old_parent_to_keep_a_neighbour() {
+        let mut structure = Structure::new(None, SemiStrict);
+        structure.add_node(node("a", &[])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.add_node(node("c", &["a"])).unwrap();
+
+        structure.move_child("b", "a", "c").unwrap();
+        let Err(MapRootError::StrictnessViolation { offending_neighbors, .. }) = structure.move_child("c", "a", "b") else {
+            panic!("a would have been left without a neighbour");
+        };
+        assert_eq!(offending_neighbors, vec!["a"]);
+    }
+
+    #[test]
+    fn dag_refuses_whatever_would_close_a_cycle_and_names_it() {
+        // a -> b -> c
+        let mut structure = Structure::new(None, Dag);
+        structure.add_node(node("a", &[])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.add_node(node("c", &["b"])).unwrap();
+
+        let Err(MapRootError::Cycle(cycle)) = structure.link("c", "a") else { panic!("c -> a closes a cycle") };
+        assert_eq!(cycle, ["c", "a", "b", "c"]);
+        let mut closing = node("d", &["c"]);
+        closing.add_child("a");
+        let Err(MapRootError::Cycle(cycle)) = structure.add_node(closing) else { panic!("d between c and a closes a cycle") };
+        assert_eq!(cycle, ["d", "a", "b", "c", "d"]);
+        assert!(matches!(structure.link("b", "b"), Err(MapRootError::Cycle(_))));
+
+        // nothing of the refused changes was kept, and a shortcut that is no cycle still goes in
+        assert!(!structure.contains_key("d"));
+        assert!(!structure.has_child_by_key("c", "a"));
+        structure.link("a", "c").unwrap();
+        structure.unlink("b", "c").unwrap();
+        structure.link("c", "b").unwrap();
+    }
+
+    #[test]
+    fn tree_refuses_every_change_that_breaks_one_parent_per_node() {
+        // r -> a -> b, r -> c
+        let mut structure = Structure::new(None, Tree);
+        structure.add_node(node("r", &[])).unwrap();
+        structure.add_node(node("a", &["r"])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.add_node(node("c", &["r"])).unwrap();
+        assert_eq!(structure.root().unwrap().key, "r");
+
+        assert!(offending(structure.add_node(node("x", &[]))).is_empty());
+        assert_eq!(offending(structure.add_node(node("x", &["a", "c"]))), ["a", "c"]);
+        let mut with_child = node("x", &["a"]);
+        with_child.add_child("c");
+        assert_eq!(offending(structure.add_node(with_child)), ["c"]);
+
+        assert_eq!(offending(structure.link("c", "b")), ["a", "c"]);
+        assert_eq!(offending(structure.link("c", "r")), ["c"]);
+        assert_eq!(offending(structure.unlink("a", "b")), ["a"]);
+        assert_eq!(offending(structure.remove_node_by_key("a")), ["b"]);
+        assert_eq!(offending(structure.set_root(Some("a"))), ["r"]);
+        assert_eq!(offending(structure.set_root(None)), Vec::<String>::new());
+
+        structure.remove_node_by_key("b").unwrap();
+        structure.remove_node_by_key("a").unwrap();
+        assert_eq!(structure.keys().collect::<Vec<_>>(), ["c", "r"]);
+    }
+
+    #[test]
+    fn rooted_names_every_node_a_change_would_cut_off_from_the_root() {
+        // r -> a -> b -> d, r -> c -> d
+        let mut structure = Structure::new(None, Rooted);
+        structure.add_node(node("r", &[])).unwrap();
+        structure.add_node(node("a", &["r"])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.add_node(node("c", &["r"])).unwrap();
+        structure.add_node(node("d", &["b", "c"])).unwrap();
+
+        assert_eq!(offending(structure.add_node(node("x", &[]))), ["x"]);
+        assert_eq!(offending(structure.unlink("r", "a")), ["a", "b"]);
+        assert_eq!(offending(structure.remove_node_by_key("a")), ["b"]);
+        assert_eq!(offending(structure.set_root(Some("c"))), ["r", "a", "b"]);
+        assert_eq!(offending(structure.set_root(None)), ["r", "a", "b", "c", "d"]);
+
+        // d can still be reached through c, so either of its parents may go
+        structure.unlink("b", "d").unwrap();
+        assert_eq!(offending(structure.unlink("c", "d")), ["d"]);
+        structure.remove_node_by_key("b").unwrap();
+    }
+
+    #[test]
+    fn connected_names_the_island_a_change_would_split_off() {
+        // a -> b -> c -> d, e -> c, a -> e
+        let mut structure = Structure::new(None, Connected);
+        structure.add_node(node("a", &[])).unwrap();
+        structure.add_node(node("b", &["a"])).unwrap();
+        structure.add_node(node("c", &["b"])).unwrap();
+        structure.add_node(node("d", &["c"])).unwrap();
+        let mut e = node("e", &["a"]);
+        e.add_child("c");
+        structure.add_node(e).unwrap();
+
+        assert_eq!(offending(structure.add_node(node("x", &[]))), ["x"]);
+        // a child alone joins the new node to the structure as well
+        let mut leading = node("y", &[]);
+        leading.add_child("a");
+        structure.add_node(leading).unwrap();
+
+        assert_eq!(offending(structure.unlink("c", "d")), ["d"]);
+        assert_eq!(offending(structure.remove_node_by_key("c")), ["d"]);
+        assert_eq!(offending(structure.remove_node_by_key("a")), ["y"]);
+        // b -> c has a way round through e, and the edge direction does not matter for that
+        structure.unlink("b", "c").unwrap();
+        assert_eq!(offending(structure.unlink("a", "b")), ["b"]);
+        structure.set_root(Some("d")).unwrap();
+    }
+}
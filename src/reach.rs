@@ -0,0 +1,174 @@
+// an optional closure table that answers whether one node can reach another without walking the graph
+// every node keeps the set of nodes below it and the set of nodes above it,
+// linking merges those sets right away, unlinking and removing recompute the nodes above the change
+// memory grows with the number of ancestor and descendant pairs, so it is off until a structure turns it on
+
+use crate::node::{IdMap, IdSet, Node, NodeId};
+use std::collections::VecDeque;
+
+#[derive(Default)]
+pub(crate) struct Reachability {
+    descendants: IdMap<IdSet>, // every node reachable by following children, a node on a cycle holds itself
+    ancestors: IdMap<IdSet>,   // the reverse of descendants
+}
+
+impl Reachability {
+    pub(crate) fn build<T: Clone>(slots: &[Option<Node<T>>]) -> Self {
+        let mut reach = Reachability::default();
+        let ids: Vec<NodeId> = live(slots).collect();
+        reach.recompute(slots, &ids);
+        reach
+    }
+
+    pub(crate) fn reaches(&self, from: NodeId, to: NodeId) -> bool {
+        self.descendants.get(&from).is_some_and(|below| below.contains(&to))
+    }
+
+    pub(crate) fn link(&mut self, parent: NodeId, child: NodeId) {
+        // everything that reaches the parent now reaches everything the child reaches
+        let mut above = self.ancestors.get(&parent).cloned().unwrap_or_default();
+        above.insert(parent);
+        let mut below = self.descendants.get(&child).cloned().unwrap_or_default();
+        below.insert(child);
+        for id in above.iter() {
+            self.descendants.entry(*id).or_default().extend(below.iter().copied());
+        }
+        for id in below.iter() {
+            self.ancestors.entry(*id).or_default().extend(above.iter().copied());
+        }
+    }
+
+    pub(crate) fn unlink<T: Clone>(&mut self, slots: &[Option<Node<T>>], parent: NodeId) {
+        // only the parent and the nodes above it can have lost anything, the edge is already gone from the slots
+        let mut affected: Vec<NodeId> = self.ancestors.get(&parent).map_or_else(Vec::new, |above| above.iter().copied().collect());
+        affected.push(parent);
+        self.recompute(slots, &affected);
+    }
+
+    pub(crate) fn remove<T: Clone>(&mut self, slots: &[Option<Node<T>>], id: NodeId) {
+        // the node is already out of the slots, drop it everywhere then recompute what used to be above it
+        let above = self.ancestors.remove(&id).unwrap_or_default();
+        let below = self.descendants.remove(&id).unwrap_or_default();
+        for other in below.iter() {
+            if let Some(set) = self.ancestors.get_mut(other) {
+                set.remove(&id);
+            }
+        }
+        for other in above.iter() {
+            if let Some(set) = self.descendants.get_mut(other) {
+                set.remove(&id);
+            }
+        }
+        let affected: Vec<NodeId> = above.into_iter().filter(|other| *other != id).collect();
+        self.recompute(slots, &affected);
+    }
+
+    fn recompute<T: Clone>(&mut self, slots: &[Option<Node<T>>], ids: &[NodeId]) {
+        for id in ids.iter() {
+            let below = walk(slots, *id);
+            let before = self.descendants.remove(id).unwrap_or_default();
+            for lost in before.difference(&below) {
+                if let Some(set) = self.ancestors.get_mut(lost) {
+                    set.remove(id);
+                }
+            }
+            for reached in below.iter() {
+                self.ancestors.entry(*reached).or_default().insert(*id);
+            }
+            self.descendants.insert(*id, below);
+        }
+    }
+}
+
+fn live<T: Clone>(slots: &[Option<Node<T>>]) -> impl Iterator<Item = NodeId> + '_ {
+    slots.iter().enumerate().filter(|(_, slot)| slot.is_some()).map(|(index, _)| NodeId(index as u32))
+}
+
+fn walk<T: Clone>(slots: &[Option<Node<T>>], from: NodeId) -> IdSet {
+    // every node below from in the current slots, from itself only if a cycle leads back to it
+    let mut reached = IdSet::default();
+    let mut queue: VecDeque<NodeId> = VecDeque::from([from]);
+    while let Some(current) = queue.pop_front() {
+        let Some(node) = slots.get(current.index()).and_then(|slot| slot.as_ref()) else { continue };
+        for child in node.children.iter() {
+            if reached.insert(*child) {
+                queue.push_back(*child);
+            }
+        }
+    }
+    reached
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::NewNode;
+    use crate::policy::UnStrict;
+    use crate::structure::Structure;
+
+    // the same changes go to a structure with the closure table and one that answers by walking the children
+    struct Pair {
+        indexed: Structure<u32>,
+        walked: Structure<u32>,
+    }
+
+    impl Pair {
+        fn apply(&mut self, change: impl Fn(&mut Structure<u32>) -> bool) -> bool {
+            let done = change(&mut self.indexed);
+            assert_eq!(change(&mut self.walked), done);
+            done
+        }
+
+        fn check(&self, keys: &[String]) {
+            let live: Vec<&String> = keys.iter().filter(|key| self.walked.find_node_by_key(key).is_some()).collect();
+            for from in live.iter() {
+                for to in live.iter() {
+                    assert_eq!(self.indexed.is_ancestor(from, to).unwrap(), self.walked.is_ancestor(from, to).unwrap(), "{} -> {}", from, to);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn closure_table_matches_a_plain_walk_through_random_changes() {
+        let keys: Vec<String> = (0..12).map(|n| n.to_string()).collect();
+        let mut pair = Pair { indexed: Structure::new(None, UnStrict), walked: Structure::new(None, UnStrict) };
+        pair.indexed.enable_reachability();
+
+        // a small lcg so the run is the same every time
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: usize| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as usize % bound
+        };
+
+        let mut deleted = 0;
+        for _ in 0..2000 {
+            let (a, b) = (keys[next(keys.len())].clone(), keys[next(keys.len())].clone());
+            match next(10) {
+                // adding after a delete takes the freed slot, so the table sees an id come back as another node
+                0..=2 => {
+                    pair.apply(|s| {
+                        let mut node = NewNode::new(a.clone(), 0);
+                        if a != b && s.find_node_by_key(&b).is_some() {
+                            node.add_parent(&b);
+                        }
+                        s.add_node(node).is_ok()
+                    });
+                }
+                3..=5 => {
+                    pair.apply(|s| a != b && s.link(&a, &b).is_ok());
+                }
+                6..=8 => {
+                    pair.apply(|s| s.unlink(&a, &b).is_ok());
+                }
+                _ => {
+                    if pair.apply(|s| s.delete_node_by_key(&a).is_ok()) {
+                        deleted += 1;
+                    }
+                }
+            }
+            pair.check(&keys);
+        }
+        assert!(deleted > 0);
+    }
+}
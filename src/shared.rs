@@ -0,0 +1,311 @@
+// a database handle that can be cloned and shared between threads
+// every structure sits behind its own reader/writer lock, so any number of threads can read a structure
+// at once while writes to it are serialized, and writes to different structures do not wait on each other
+// the write-ahead log and checkpoint settings sit behind a single mutex so records go out one at a time
+//
+// locks are always taken in the same order to rule out deadlocks
+//     the list of structures, then the structure itself, then the log
+// a checkpoint takes a read lock on every structure before the log, so the snapshot it writes
+// holds exactly the operations the log has seen up to that point
+
+use crate::database::{execute, Applied, CheckpointPolicy, LogState, PrimInitDatabase, PrimInitStructureWrapper};
+use crate::node::{Edge, NewNode, NodeId};
+use crate::structure::Structure;
+use crate::error::{MapRootError, Result};
+use crate::policy::PolicyRegistry;
+use crate::index::IndexValue;
+use crate::format::ValueTag;
+use crate::wal::WalOp;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type StructureLock<T> = Arc<RwLock<Structure<T>>>;
+
+struct Shared<T: Clone> {
+    structures: RwLock<Vec<(String, StructureLock<T>)>>,
+    log: Mutex<LogState>,
+}
+
+pub struct SharedDatabase<T: Clone> {
+    inner: Arc<Shared<T>>,
+}
+
+// a lock is only poisoned by a panic while it was held, every mutation either finishes or leaves the
+// structure untouched, so the data behind it is still usable and the lock is simply taken over
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<T: Clone> Clone for SharedDatabase<T> {
+    fn clone(&self) -> Self {
+        SharedDatabase { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<T: Clone> From<PrimInitDatabase<T>> for SharedDatabase<T> {
+    fn from(database: PrimInitDatabase<T>) -> Self {
+        let (data, log) = database.into_parts();
+        let structures = data.into_iter().map(|wrapper| (wrapper.name, Arc::new(RwLock::new(wrapper.structure)))).collect();
+        SharedDatabase {
+            inner: Arc::new(Shared {
+                structures: RwLock::new(structures),
+                log: Mutex::new(log),
+            }),
+        }
+    }
+}
+
+impl<T: Clone> Default for SharedDatabase<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> SharedDatabase<T> {
+    pub fn new() -> Self {
+        PrimInitDatabase::new().into()
+    }
+
+    pub fn checkpoint_policy(&self) -> CheckpointPolicy {
+        lock(&self.inner.log).policy
+    }
+
+    pub fn set_checkpoint_policy(&self, policy: CheckpointPolicy) {
+        lock(&self.inner.log).policy = policy;
+    }
+
+    pub fn last_checkpoint_error(&self) -> Option<Arc<MapRootError>> {
+        // shared between handles, so the error is handed out behind an Arc instead of borrowed from the lock
+        lock(&self.inner.log).checkpoint_error.clone()
+    }
+
+    pub fn structure_names(&self) -> Vec<String> {
+        read(&self.inner.structures).iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn read<R>(&self, name: &str, f: impl FnOnce(&Structure<T>) -> R) -> Result<R> {
+        // run f against the structure under a read lock, other readers of the structure are not blocked
+        let structure = self.find(name).ok_or_else(|| MapRootError::UnknownStructure(name.to_string()))?;
+        let guard = read(&structure);
+        Ok(f(&guard))
+    }
+
+    pub fn register_alt_key<F>(&self, structure: &str, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<String> + Send + Sync + 'static,
+    {
+        // indexes are not logged since the extractor can not be saved, register them again after every open
+        let target = self.find(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?;
+        let mut guard = write(&target);
+        guard.register_alt_key(name, extractor)
+    }
+
+    pub fn register_index<F>(&self, structure: &str, name: &str, unique: bool, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<IndexValue> + Send + Sync + 'static,
+    {
+        let target = self.find(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?;
+        let mut guard = write(&target);
+        guard.register_index(name, unique, extractor)
+    }
+
+    pub fn enable_reachability(&self, structure: &str) -> Result<()> {
+        // like indexes this is not logged, turn it on again after every open
+        let target = self.find(structure).ok_or_else(|| MapRootError::UnknownStructure(structure.to_string()))?;
+        write(&target).enable_reachability();
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<StructureLock<T>> {
+        // the structure is handed out on its own so the list lock is not held while it is in use
+        read(&self.inner.structures).iter().find(|(n, _)| n == name).map(|(_, structure)| Arc::clone(structure))
+    }
+}
+
+impl<T: Clone + Eq + Serialize + ValueTag> SharedDatabase<T> {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        // every structure is read locked before the log, so the file holds exactly what the log has seen
+        let structures = read(&self.inner.structures);
+        let guards: Vec<RwLockReadGuard<'_, Structure<T>>> = structures.iter().map(|(_, structure)| read(structure)).collect();
+        let named: Vec<(&str, &Structure<T>)> = structures.iter().zip(guards.iter()).map(|((name, _), guard)| (name.as_str(), &**guard)).collect();
+        lock(&self.inner.log).save(path.as_ref(), &named)
+    }
+
+    pub fn checkpoint(&self) -> Result<()> {
+        let structures = read(&self.inner.structures);
+        let guards: Vec<RwLockReadGuard<'_, Structure<T>>> = structures.iter().map(|(_, structure)| read(structure)).collect();
+        let named: Vec<(&str, &Structure<T>)> = structures.iter().zip(guards.iter()).map(|((name, _), guard)| (name.as_str(), &**guard)).collect();
+        lock(&self.inner.log).checkpoint(&named)
+    }
+
+    pub fn add_structure(&self, structure: PrimInitStructureWrapper<T>) -> Result<()> {
+        let due = {
+            let mut structures = write(&self.inner.structures);
+            if structures.iter().any(|(name, _)| *name == structure.name) {
+                return Err(MapRootError::DuplicateKey(structure.name));
+            }
+            let op = WalOp::<T>::AddStructure { name: structure.name.clone(), snapshot: structure.structure.serialize_snapshot()? };
+            let due = lock(&self.inner.log).append(&op)?;
+            structures.push((structure.name, Arc::new(RwLock::new(structure.structure))));
+            due
+        };
+        self.checkpoint_if(due);
+        Ok(())
+    }
+
+    pub fn add_node(&self, structure: &str, node: NewNode<T>) -> Result<NodeId> {
+        self.run(WalOp::add_node(structure, node)).map(Applied::id)
+    }
+
+    pub fn upsert(&self, structure: &str, node: NewNode<T>) -> Result<NodeId> {
+        self.run(WalOp::upsert(structure, node)).map(Applied::id)
+    }
+
+    pub fn replace(&self, structure: &str, node: NewNode<T>) -> Result<NodeId> {
+        self.run(WalOp::replace(structure, node)).map(Applied::id)
+    }
+
+    pub fn delete_node(&self, structure: &str, key: &str) -> Result<()> {
+        self.run(WalOp::delete_node(structure, key)).map(|_| ())
+    }
+
+    pub fn remove_node(&self, structure: &str, key: &str) -> Result<NewNode<T>> {
+        self.run(WalOp::remove_node(structure, key)).map(Applied::removed)
+    }
+
+    pub fn link(&self, structure: &str, parent: &str, child: &str) -> Result<()> {
+        self.run(WalOp::link(structure, parent, child)).map(|_| ())
+    }
+
+    pub fn link_with(&self, structure: &str, parent: &str, child: &str, edge: Edge) -> Result<()> {
+        self.run(WalOp::link_with(structure, parent, child, edge)).map(|_| ())
+    }
+
+    pub fn unlink(&self, structure: &str, parent: &str, child: &str) -> Result<()> {
+        self.run(WalOp::unlink(structure, parent, child)).map(|_| ())
+    }
+
+    pub fn edit_value(&self, structure: &str, key: &str, value: T) -> Result<()> {
+        self.run(WalOp::edit_value(structure, key, value)).map(|_| ())
+    }
+
+    pub fn rename(&self, structure: &str, old_key: &str, new_key: &str) -> Result<()> {
+        self.run(WalOp::rename(structure, old_key, new_key)).map(|_| ())
+    }
+
+    pub fn set_root(&self, structure: &str, root: Option<&str>) -> Result<()> {
+        self.run(WalOp::set_root(structure, root)).map(|_| ())
+    }
+
+    pub fn move_child(&self, structure: &str, child: &str, from: &str, to: &str) -> Result<()> {
+        self.run(WalOp::move_child(structure, child, from, to)).map(|_| ())
+    }
+
+    fn run(&self, op: WalOp<T>) -> Result<Applied<T>> {
+        // the write lock of the structure is held from the change until it is logged,
+        // so the log sees the changes to a structure in the order they were made
+        let (applied, due) = {
+            let structures = read(&self.inner.structures);
+            let name = op.structure();
+            let Some((_, structure)) = structures.iter().find(|(n, _)| n == name) else {
+                return Err(MapRootError::UnknownStructure(name.to_string()));
+            };
+            lock(&self.inner.log).usable()?;
+            let mut guard = write(structure);
+            let applied = execute(&mut guard, &op)?;
+            (applied, lock(&self.inner.log).append(&op)?)
+        };
+        // the checkpoint has to wait until the structure lock is released since it reads every structure
+        self.checkpoint_if(due);
+        Ok(applied)
+    }
+
+    fn checkpoint_if(&self, due: bool) {
+        // the operation is already safe in the log, so a checkpoint that fails here is not its failure
+        if due {
+            if let Err(e) = self.checkpoint() {
+                lock(&self.inner.log).checkpoint_error = Some(Arc::new(e));
+            }
+        }
+    }
+}
+
+impl<T: Clone + Eq + Serialize + DeserializeOwned + ValueTag + 'static> SharedDatabase<T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        // load the database file and replay its log the same way PrimInitDatabase::open does
+        PrimInitDatabase::open(path).map(SharedDatabase::from)
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, registry: &PolicyRegistry<T>) -> Result<Self> {
+        PrimInitDatabase::open_with(path, registry).map(SharedDatabase::from)
+    }
+
+}
+
+// the handle is only useful if it can cross threads, this stops compiling if that is ever no longer true
+const _: fn() = || {
+    fn assert_send_sync<S: Send + Sync>() {}
+    assert_send_sync::<SharedDatabase<String>>();
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::UnStrict;
+    use std::fs;
+
+    #[test]
+    fn failed_log_write_makes_every_handle_refuse_changes() {
+        let dir = std::env::temp_dir().join(format!("maprootdb-shared-failed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.mrdb");
+
+        let db: SharedDatabase<u32> = SharedDatabase::open(&path).unwrap();
+        db.add_structure(PrimInitStructureWrapper::new("s".to_string(), Structure::new(None, UnStrict))).unwrap();
+        db.add_node("s", NewNode::new("a".to_string(), 1)).unwrap();
+        lock(&db.inner.log).wal.as_mut().unwrap().break_writes();
+
+        let other = db.clone();
+        assert!(matches!(db.add_node("s", NewNode::new("b".to_string(), 2)), Err(MapRootError::Io(_))));
+        assert!(matches!(other.add_node("s", NewNode::new("c".to_string(), 3)), Err(MapRootError::LogFailed)));
+        assert!(matches!(other.checkpoint(), Err(MapRootError::LogFailed)));
+        drop((db, other));
+
+        let db: SharedDatabase<u32> = SharedDatabase::open(&path).unwrap();
+        assert_eq!(db.read("s", |s| s.len()).unwrap(), 1);
+        assert!(matches!(db.read("t", |s| s.len()), Err(MapRootError::UnknownStructure(name)) if name == "t"));
+    }
+
+    #[test]
+    fn failed_automatic_checkpoint_is_seen_by_every_handle() {
+        let dir = std::env::temp_dir().join(format!("maprootdb-shared-policy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.mrdb");
+
+        let db: SharedDatabase<u32> = SharedDatabase::open(&path).unwrap();
+        db.add_structure(PrimInitStructureWrapper::new("s".to_string(), Structure::new(None, UnStrict))).unwrap();
+        db.set_checkpoint_policy(CheckpointPolicy { max_log_bytes: None, max_log_ops: Some(2) });
+        fs::create_dir(dir.join("test.mrdb.tmp")).unwrap();
+
+        let other = db.clone();
+        other.add_node("s", NewNode::new("a".to_string(), 1)).unwrap();
+        assert!(matches!(db.last_checkpoint_error().as_deref(), Some(MapRootError::Io(_))));
+
+        fs::remove_dir(dir.join("test.mrdb.tmp")).unwrap();
+        db.add_node("s", NewNode::new("b".to_string(), 2)).unwrap();
+        assert!(other.last_checkpoint_error().is_none());
+        assert!(lock(&db.inner.log).wal.as_ref().unwrap().is_empty());
+    }
+}
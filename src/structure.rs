@@ -1,315 +1,1279 @@
-use std::collections::HashMap;
-use crate::node::{NodeRef}; // Update import to use NodeRef
-use serde::ser::{Serialize, Serializer, SerializeStruct};
-use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor, MapAccess, Error as DeError};
-use std::fmt;
-use bincode::{serialize, deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use crate::node::{self, Edge, IdMap, IdSet, NewNode, Node, NodeId};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::format::{self, decode, encode, FileKind, FormatError, ValueTag};
+use crate::error::{MapRootError, Result};
+use crate::policy::{PolicyRegistry, StructurePolicy};
+use crate::index::{AltKeyExtractor, AltKeyIndex, IndexExtractor, IndexValue, SecondaryIndex};
+use crate::traverse::{neighbours, Direction, Walk};
+use crate::reach::Reachability;
+use std::ops::{Bound, RangeBounds};
 
 
-// add a way to have a hashmap that lets select aspects of the node.value to map to the NodeRef as a whole 
+// bumped whenever the structure header or the snapshot layout changes
+// version 2 moved snapshots into the checksummed container described in format.rs
+// version 3 added the labeled edges to node records
+pub const STRUCTURE_FORMAT_VERSION: u32 = 3;
 
+// the structure level settings that are saved in front of the node records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StructureHeader {
+    pub version: u32,
+    pub mode: String,
+    pub root: Option<String>, // key of the root node if there is one
+    pub has_first_node: bool,
+}
+
+// one page of a key ordered scan, pass next back in as the cursor to get the page after it
+// next is None once the scan is done
+pub struct KeyPage<'a, T: Clone> {
+    pub nodes: Vec<&'a Node<T>>,
+    pub next: Option<String>,
+}
 
+fn after<'a>(start: Bound<&'a str>, cursor: Option<&'a str>) -> Bound<&'a str> {
+    // resume right after the cursor unless the range starts later anyway
+    match (start, cursor) {
+        (start @ (Bound::Included(key) | Bound::Excluded(key)), Some(cursor)) if key > cursor => start,
+        (_, Some(cursor)) => Bound::Excluded(cursor),
+        (start, None) => start,
+    }
+}
+
+fn page<'a, T: Clone>(mut nodes: impl Iterator<Item = &'a Node<T>>, limit: usize) -> Result<KeyPage<'a, T>> {
+    // an empty page has no last key to hand out as the cursor, so it could only end the scan early
+    if limit == 0 {
+        return Err(MapRootError::ZeroLimit);
+    }
+    let taken: Vec<&Node<T>> = nodes.by_ref().take(limit).collect();
+    let next = match (taken.last(), nodes.next()) {
+        (Some(last), Some(_)) => Some(last.key.clone()),
+        _ => None,
+    };
+    Ok(KeyPage { nodes: taken, next })
+}
 
+// the structure owns every node in an arena of slots addressed by NodeId
+// edges are stored as ids on both ends, so nothing is reference counted and deleting a node frees it
 pub struct Structure<T: Clone> {
-    pub root: Option<NodeRef<T>>,           // Use NodeRef for root
-    pub nodes: HashMap<String, NodeRef<T>>, // main hashmap for the structure that hashes to NodeRefs 
-    pub mode: String,
+    root: Option<NodeId>,
+    slots: Vec<Option<Node<T>>>,    // the arena, a deleted node leaves its slot empty until it is reused
+    free: Vec<NodeId>,              // empty slots waiting to be reused
+    index: BTreeMap<String, NodeId>, // main map for the structure from keys to arena ids, ordered so keys can be scanned
+    policy: Box<dyn StructurePolicy<T>>, // the rules every change has to pass, see policy.rs
+    alt_keys: HashMap<String, AltKeyIndex<T>>, // alternate keys by index name, see index.rs
+    indexes: HashMap<String, SecondaryIndex<T>>, // ordered secondary indexes by name
+    reach: Option<Reachability>, // closure table for ancestor questions when turned on, see reach.rs
     pub has_first_node: bool,
 }
 
-impl<T: Clone + Eq + Serialize> Structure<T> {
-    pub fn new(root: Option<NodeRef<T>>, mode: String) -> Self {
-        let mut nodes: HashMap<String, NodeRef<T>> = HashMap::new();
-        let mut has_first_node: bool = false;
-
-        if let Some(root) = &root {
-            nodes.insert(root.key(), root.rc_clone()); // Insert root node
-            has_first_node = true;
-        }
-     
-        // iterate through the alt_nodes and store all the keys that will be used to hash to the node
-        
-
-        Structure {
-            root,
-            nodes,
-            mode,
-            has_first_node,
-             
-        }
-    }
-
-    // for the add node method we will have 2 modes semi-strict and un-strict, 
-    // semi strict means at least one parent or child must be present in the structure, unless the node is the first node in the structure
-    // un-strict means that the node can be added without any parents or children
-    // more modes will be added but this is good to get it going
-    
-   
-    pub fn serialize_related_ids(&self) -> Vec<u8>{
-        // in this function I am seraialing all the keys of the given hashmap so that I can rebuild by grabbing all the nodes by key allowing for rebuild
-        let mut keys: Vec<String> = Vec::new(); 
-        for (key, _) in self.nodes.iter(){
-            keys.push(key.clone()); 
+impl<T: Clone> Structure<T> {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn id_of(&self, key: &str) -> Option<NodeId> {
+        self.index.get(key).copied()
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node<T>> {
+        self.slots.get(id.index()).and_then(|slot| slot.as_ref())
+    }
+
+    pub fn key_of(&self, id: NodeId) -> Option<&str> {
+        self.node(id).map(|node| node.key.as_str())
+    }
+
+    pub fn find_node_by_key(&self, key: &str) -> Option<&Node<T>> {
+        // find a node in the structure by key using the hashmap 
+        // return the reference to the node if found
+        // return None if not found 
+        self.id_of(key).and_then(|id| self.node(id))
+    }
+
+    pub fn mode(&self) -> &str {
+        // the name of the policy, this is what snapshots save as the mode
+        self.policy.name()
+    }
+
+    pub fn policy(&self) -> &dyn StructurePolicy<T> {
+        self.policy.as_ref()
+    }
+
+    pub fn root(&self) -> Option<&Node<T>> {
+        self.root.and_then(|id| self.node(id))
+    }
+
+    pub fn root_id(&self) -> Option<NodeId> {
+        self.root
+    }
+
+    pub fn set_root(&mut self, key: Option<&str>) -> Result<()> {
+        // point the root at a node of the structure, or clear it with None
+        let root = match key {
+            Some(key) => Some(self.lookup(key)?),
+            None => None,
+        };
+        self.policy.before_set_root(self, root)?;
+        self.root = root;
+        Ok(())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        // in key order
+        self.index.keys().map(|key| key.as_str())
+    }
+
+    pub fn range<'a, R: RangeBounds<&'a str>>(&self, range: R) -> impl Iterator<Item = &Node<T>> {
+        // the nodes with keys inside the range, in key order
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.scan(start, end).map(|id| self.slot(id))
+    }
+
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a Node<T>> {
+        // the nodes whose key starts with the prefix, in key order
+        self.scan(Bound::Included(prefix), Bound::Unbounded)
+            .map(|id| self.slot(id))
+            .take_while(move |node| node.key.starts_with(prefix))
+    }
+
+    pub fn range_page<'a, R: RangeBounds<&'a str>>(&self, range: R, cursor: Option<&str>, limit: usize) -> Result<KeyPage<'_, T>> {
+        // at most limit nodes of the range that come after the cursor, the cursor is the next of the previous page
+        let start = after(range.start_bound().cloned(), cursor);
+        let end = range.end_bound().cloned();
+        page(self.scan(start, end).map(|id| self.slot(id)), limit)
+    }
+
+    pub fn scan_prefix_page(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> Result<KeyPage<'_, T>> {
+        let start = after(Bound::Included(prefix), cursor);
+        let nodes = self.scan(start, Bound::Unbounded).map(|id| self.slot(id)).take_while(|node| node.key.starts_with(prefix));
+        page(nodes, limit)
+    }
+
+    fn scan(&self, start: Bound<&str>, end: Bound<&str>) -> impl Iterator<Item = NodeId> + '_ {
+        // the btree panics on a range that ends before it starts, a cursor past the end makes one, so that is just empty
+        let empty = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        let range = if empty { None } else { Some(self.index.range::<str, _>((start, end))) };
+        range.into_iter().flatten().map(|(_, id)| *id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &Node<T>)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| slot.as_ref().map(|node| (NodeId(index as u32), node)))
+    }
+
+    pub fn parents(&self, key: &str) -> Vec<&Node<T>> {
+        self.find_node_by_key(key).map_or_else(Vec::new, |node| node.parents.iter().map(|id| self.slot(*id)).collect())
+    }
+
+    pub fn children(&self, key: &str) -> Vec<&Node<T>> {
+        self.find_node_by_key(key).map_or_else(Vec::new, |node| node.children.iter().map(|id| self.slot(*id)).collect())
+    }
+
+    pub fn edge(&self, parent_key: &str, child_key: &str) -> Result<Option<&Edge>> {
+        // the label of the edge between two nodes, None for a plain edge
+        let (parent, child) = (self.lookup(parent_key)?, self.lookup(child_key)?);
+        if !self.slot(parent).has_child(child) {
+            return Err(MapRootError::EdgeNotFound { parent: parent_key.to_string(), child: child_key.to_string() });
         }
+        Ok(self.slot(parent).edge_to(child))
+    }
 
-        // seraialize with bincode
-        let serialized = serialize(&keys).unwrap();
+    pub fn children_labeled(&self, key: &str, label: &str) -> Vec<&Node<T>> {
+        // the children the node reaches over an edge with this label
+        self.find_node_by_key(key).map_or_else(Vec::new, |node| {
+            node.edges.iter().filter(|(_, edge)| edge.label == label).map(|(child, _)| self.slot(*child)).collect()
+        })
+    }
 
-        // now lets retun this vector
-        serialized 
+    pub fn parents_labeled(&self, key: &str, label: &str) -> Vec<&Node<T>> {
+        let Some(id) = self.id_of(key) else { return Vec::new() };
+        self.slot(id)
+            .parents
+            .iter()
+            .filter(|parent| self.slot(**parent).edge_to(id).is_some_and(|edge| edge.label == label))
+            .map(|parent| self.slot(*parent))
+            .collect()
+    }
 
+    pub fn ancestors(&self, key: &str) -> Result<Walk<'_, T>> {
+        // every node above the key, breadth first unless the walk is switched to depth first
+        Ok(Walk::new(self, self.lookup(key)?, Direction::Parents))
     }
 
-    pub fn serialize_related_nodes(&self) -> Vec<Vec<u8>>{
-        // we can use the seralize function that I wrote for indiv nodes
-        let mut over_vector: Vec<Vec<u8>> = Vec::new(); 
+    pub fn descendants(&self, key: &str) -> Result<Walk<'_, T>> {
+        Ok(Walk::new(self, self.lookup(key)?, Direction::Children))
+    }
+
+    pub fn shortest_path(&self, from: &str, to: &str, direction: Option<Direction>) -> Result<Option<Vec<String>>> {
+        // the keys on a shortest path between the two nodes with both ends included, None if there is no path
+        // follows children unless another direction is given
+        let direction = direction.unwrap_or(Direction::Children);
+        let (start, target) = (self.lookup(from)?, self.lookup(to)?);
+        let mut came_from: IdMap<NodeId> = IdMap::default();
+        let mut queue = VecDeque::from([start]);
+        came_from.insert(start, start);
+
+        while let Some(current) = queue.pop_front() {
+            if current == target {
+                let mut path = vec![self.slot(current).key.clone()];
+                let mut step = current;
+                while step != start {
+                    step = came_from[&step];
+                    path.push(self.slot(step).key.clone());
+                }
+                path.reverse();
+                return Ok(Some(path));
+            }
+            for next in neighbours(self.slot(current), direction) {
+                if let Entry::Vacant(entry) = came_from.entry(next) {
+                    entry.insert(current);
+                    queue.push_back(next);
+                }
+            }
+        }
+        Ok(None)
+    }
 
-        // iterate through the map to get the vectors for each node
-        for (_, node) in self.nodes.iter(){
-            // clone the reference
-            let n: NodeRef<T> = node.rc_clone(); 
-            let serialized: Vec<u8> = n.serialize_node();
-            over_vector.push(serialized);
+    pub fn lowest_common_ancestors(&self, a: &str, b: &str, direction: Option<Direction>) -> Result<Vec<String>> {
+        // the merge bases of two nodes in key order, a node counts as its own ancestor
+        // following parents by default, with children this finds the highest common descendants instead
+        // with either direction every connected node is common and none is lower than another, so that is refused
+        let direction = direction.unwrap_or(Direction::Parents);
+        if direction == Direction::Either {
+            return Err(MapRootError::UnsupportedDirection(direction));
         }
-        over_vector
+        let from_a = self.distances(self.lookup(a)?, direction);
+        let from_b = self.distances(self.lookup(b)?, direction);
+        let common: IdSet = from_a.keys().filter(|id| from_b.contains_key(id)).copied().collect();
 
+        // a common ancestor with a common ancestor below it is not lowest,
+        // every node between the two is common too, so checking one step back is enough
+        let back = if direction == Direction::Parents { Direction::Children } else { Direction::Parents };
+        let mut lowest: Vec<String> = common
+            .iter()
+            .filter(|id| !neighbours(self.slot(**id), back).any(|next| next != **id && common.contains(&next)))
+            .map(|id| self.slot(*id).key.clone())
+            .collect();
+        lowest.sort();
+        Ok(lowest)
     }
 
+    fn distances(&self, from: NodeId, direction: Direction) -> IdMap<usize> {
+        // breadth first distance to every node reachable from the start, the start is at 0
+        let mut distances: IdMap<usize> = IdMap::default();
+        let mut queue = VecDeque::from([from]);
+        distances.insert(from, 0);
+        while let Some(current) = queue.pop_front() {
+            let distance = distances[&current];
+            for next in neighbours(self.slot(current), direction) {
+                distances.entry(next).or_insert_with(|| {
+                    queue.push_back(next);
+                    distance + 1
+                });
+            }
+        }
+        distances
+    }
+
+    pub fn enable_reachability(&mut self) {
+        // build the closure table, from here on ancestor questions are answered from it and every change keeps it current
+        if self.reach.is_none() {
+            self.reach = Some(Reachability::build(&self.slots));
+        }
+    }
+
+    pub fn disable_reachability(&mut self) {
+        self.reach = None;
+    }
+
+    pub fn has_reachability(&self) -> bool {
+        self.reach.is_some()
+    }
+
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        // whether following children from the ancestor leads to the descendant
+        // a node is only its own ancestor when it sits on a cycle
+        let (from, to) = (self.lookup(ancestor)?, self.lookup(descendant)?);
+        if let Some(reach) = self.reach.as_ref() {
+            return Ok(reach.reaches(from, to));
+        }
+        let start: Vec<NodeId> = self.slot(from).children.iter().copied().collect();
+        Ok(self.child_path(&start, &IdSet::from_iter([to])).is_some())
+    }
+
+    pub fn is_descendant(&self, descendant: &str, ancestor: &str) -> Result<bool> {
+        self.is_ancestor(ancestor, descendant)
+    }
+
+    pub fn topo_order(&self) -> Result<Vec<&Node<T>>> {
+        // every node with all of its parents before it, fails with the cycle if there is one
+        Ok(self.topo_levels()?.into_iter().flatten().collect())
+    }
+
+    pub fn topo_levels(&self) -> Result<Vec<Vec<&Node<T>>>> {
+        // waves of nodes whose parents are all in earlier waves, the nodes of one wave do not depend on each other
+        // each wave is in key order so the result is stable
+        let mut waiting: IdMap<usize> = self.nodes().map(|(id, node)| (id, node.parents.len())).collect();
+        let mut wave: Vec<NodeId> = waiting.iter().filter(|(_, count)| **count == 0).map(|(id, _)| *id).collect();
+        let mut levels = Vec::new();
 
-    fn semi_strict_check_for_one(&self, node : NodeRef<T>, off_limit_key: &str) -> bool {
-        
+        while !wave.is_empty() {
+            let mut next = Vec::new();
+            for id in wave.iter() {
+                waiting.remove(id);
+                for child in self.slot(*id).children.iter() {
+                    let count = waiting.get_mut(child).expect("child of a node is part of the structure");
+                    *count -= 1;
+                    if *count == 0 {
+                        next.push(*child);
+                    }
+                }
+            }
+            let mut level: Vec<&Node<T>> = wave.iter().map(|id| self.slot(*id)).collect();
+            level.sort_by(|a, b| a.key.cmp(&b.key));
+            levels.push(level);
+            wave = next;
+        }
 
-        let node_children: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.children();
-        let node_parents: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.parents(); 
-        if node_parents.len() == 1 && node_children.len() == 1 {
-            return false
+        match waiting.keys().next() {
+            Some(stuck) => Err(MapRootError::Cycle(self.cycle_through(*stuck, &waiting))),
+            None => Ok(levels),
         }
+    }
 
-        // iterate through node children looking for a valid node exit with true if found 
-        // rembember to exclude the off limit key
-        for child in node_children.iter() {
-            // if the key is part of the structure and is not the off limit key return true
-            if  child.key() != off_limit_key && self.nodes.contains_key(&child.key()) {
-                return true
+    fn cycle_through(&self, start: NodeId, stuck: &IdMap<usize>) -> Vec<String> {
+        // every node left over by the sort has a parent that was left over too,
+        // so following those parents up from any of them has to come back around
+        let mut path = vec![start];
+        let mut seen: IdMap<usize> = IdMap::default();
+        let mut current = start;
+        loop {
+            seen.insert(current, path.len() - 1);
+            current = *self.slot(current).parents.iter().find(|parent| stuck.contains_key(parent)).expect("a stuck node has a stuck parent");
+            if let Some(at) = seen.get(&current) {
+                // walked up the cycle, turn it around so it reads parent to child
+                let mut cycle: Vec<String> = path[*at..].iter().map(|id| self.slot(*id).key.clone()).collect();
+                cycle.push(self.slot(current).key.clone());
+                cycle.reverse();
+                return cycle;
             }
+            path.push(current);
         }
-        // do the same for the parents
-        for parent in node_parents.iter() {
-            // if the key is part of the structure and is not the off limit key return true
-            if  parent.key() != off_limit_key && self.nodes.contains_key(&parent.key()) {
-                return true
+    }
+
+    pub fn parent_of(&self, key: &str) -> Result<Option<&Node<T>>> {
+        // the one parent of a node, None for a node without parents like the root
+        // in tree mode every node has at most one parent, in other modes a node with several is an error
+        let node = self.slot(self.lookup(key)?);
+        match node.parents.len() {
+            0 => Ok(None),
+            1 => Ok(node.parents.iter().next().map(|parent| self.slot(*parent))),
+            _ => Err(MapRootError::MultipleParents {
+                key: key.to_string(),
+                parents: node.parents.iter().map(|parent| self.slot(*parent).key.clone()).collect(),
+            }),
+        }
+    }
+
+    pub fn path_to_root(&self, key: &str) -> Result<Vec<&Node<T>>> {
+        // follow parent_of up from the node until a node without a parent, both ends included
+        // a chain of single parents that loops back on itself is reported as a cycle
+        let mut path = vec![self.slot(self.lookup(key)?)];
+        let mut seen: HashSet<&str> = HashSet::from([key]);
+        while let Some(parent) = self.parent_of(&path[path.len() - 1].key)? {
+            if !seen.insert(parent.key.as_str()) {
+                let mut cycle: Vec<String> = path.iter().skip_while(|node| node.key != parent.key).map(|node| node.key.clone()).collect();
+                cycle.push(parent.key.clone());
+                return Err(MapRootError::Cycle(cycle));
             }
-        }    
-        // if all else fails then return false
-        return false
-        
+            path.push(parent);
+        }
+        Ok(path)
+    }
+
+    pub fn has_parent(&self, id: NodeId, parent: NodeId) -> bool {
+        // the id versions skip both key lookups, for callers holding on to the ids add_node handed back
+        self.node(id).is_some_and(|node| node.has_parent(parent))
+    }
 
+    pub fn has_child(&self, id: NodeId, child: NodeId) -> bool {
+        self.node(id).is_some_and(|node| node.has_child(child))
     }
 
-    pub fn delete_node_by_key(&mut self, key: &str) -> bool {
-        // remove a node from the structure by key
-        // must also delete the node from the parents and children of other nodes
-        // must also also delete the node from the root if it is the root
-        // finally use the node module to fully delete the node
-        // the delete method from the node module will handle the deletion of the node from itself and its children and parents
-        // also must see if the operaton breaks the current strictness of the structure
-        // if the structure is semi-strict and the node being deleted is the last node in the structure then the structure will no longer be semi-strict and the deletion will fail with a return of false
-        // return false if the node is not found
+    pub fn has_parent_by_key(&self, key: &str, parent_key: &str) -> bool {
+        match (self.find_node_by_key(key), self.id_of(parent_key)) {
+            (Some(node), Some(parent)) => node.has_parent(parent),
+            _ => false,
+        }
+    }
 
-        // grab all the possible hashmaps from the hashmap of hashmaps 
+    pub fn has_child_by_key(&self, key: &str, child_key: &str) -> bool {
+        match (self.find_node_by_key(key), self.id_of(child_key)) {
+            (Some(node), Some(child)) => node.has_child(child),
+            _ => false,
+        }
+    }
 
+    pub fn edit_value(&mut self, key: &str, value: T) -> Result<()> {
+        let id = self.lookup(key)?;
+        self.set_value(id, value)
+    }
 
-        let prim_node = self.find_node_by_key(key);
-        if prim_node.is_none() {
-            return false
+    pub fn register_alt_key<F>(&mut self, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<String> + Send + Sync + 'static,
+    {
+        // index every node by the key the extractor gives its value, from here on the index follows every change
+        // fails if the name is taken or two nodes already produce the same alternate key
+        if self.alt_keys.contains_key(name) {
+            return Err(MapRootError::DuplicateKey(name.to_string()));
         }
-        let mut node: NodeRef<T> = prim_node.unwrap(); 
-        if (self.mode == "un-strict") {
-            self.nodes.remove(key);
-            if self.root.is_some() && self.root.as_ref().unwrap().key() == key {
-                self.root = None;
+        let extractor: AltKeyExtractor<T> = Box::new(extractor);
+        let mut index = AltKeyIndex::new(extractor);
+        for (id, node) in self.nodes() {
+            if let Some(key) = index.conflict(&node.value, Some(id)) {
+                return Err(MapRootError::DuplicateKey(format!("{}:{}", name, key)));
             }
-            if self.nodes.len() == 0 {
-                self.has_first_node = false;
+            index.insert(id, &node.value);
+        }
+        self.alt_keys.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn remove_alt_key(&mut self, name: &str) -> Result<()> {
+        self.alt_keys.remove(name).map(|_| ()).ok_or_else(|| MapRootError::UnknownIndex(name.to_string()))
+    }
+
+    pub fn find_node_by_alt_key(&self, index_name: &str, key: &str) -> Result<Option<&Node<T>>> {
+        // the node whose value produced the alternate key in the named index
+        let index = self.alt_keys.get(index_name).ok_or_else(|| MapRootError::UnknownIndex(index_name.to_string()))?;
+        Ok(index.get(key).map(|id| self.slot(id)))
+    }
+
+    pub fn register_index<F>(&mut self, name: &str, unique: bool, extractor: F) -> Result<()>
+    where
+        F: Fn(&T) -> Option<IndexValue> + Send + Sync + 'static,
+    {
+        // order every node by the value the extractor gives it, a unique index refuses a second node with the same value
+        if self.indexes.contains_key(name) {
+            return Err(MapRootError::DuplicateKey(name.to_string()));
+        }
+        let extractor: IndexExtractor<T> = Box::new(extractor);
+        let mut index = SecondaryIndex::new(extractor, unique);
+        for (id, node) in self.nodes() {
+            if let Some(key) = index.conflict(&node.value, Some(id)) {
+                return Err(MapRootError::DuplicateKey(format!("{}:{}", name, key)));
             }
-            node.delete_node();
-            return true
+            index.insert(id, &node.value);
         }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    pub fn remove_index(&mut self, name: &str) -> Result<()> {
+        self.indexes.remove(name).map(|_| ()).ok_or_else(|| MapRootError::UnknownIndex(name.to_string()))
+    }
+
+    pub fn find_by_index(&self, index_name: &str, value: &IndexValue) -> Result<Vec<&Node<T>>> {
+        self.index_range(index_name, value.clone()..=value.clone())
+    }
+
+    pub fn index_range<R: RangeBounds<IndexValue>>(&self, index_name: &str, range: R) -> Result<Vec<&Node<T>>> {
+        // nodes in order of their indexed value, nodes sharing a value come out in id order
+        let index = self.secondary(index_name)?;
+        Ok(index.range(range).map(|id| self.slot(id)).collect())
+    }
+
+    pub fn index_prefix<V: Into<IndexValue>>(&self, index_name: &str, prefix: V) -> Result<Vec<&Node<T>>> {
+        // nodes whose indexed text, bytes or tuple starts with the prefix, in value order
+        let index = self.secondary(index_name)?;
+        Ok(index.prefix(prefix.into()).map(|id| self.slot(id)).collect())
+    }
 
-        // if the we are in a semi-strict db and the node is the last node then the removal is valid 
-        let parents: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.parents(); 
-        let children: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.children();
-        
-        if parents.len() == 0 && children.len() == 0 && !self.has_first_node {
-            return false
-        } else if parents.len() == 0 && children.len() == 0 && self.has_first_node {
-            return true
+    fn secondary(&self, name: &str) -> Result<&SecondaryIndex<T>> {
+        self.indexes.get(name).ok_or_else(|| MapRootError::UnknownIndex(name.to_string()))
+    }
+
+    pub fn rename(&mut self, old_key: &str, new_key: &str) -> Result<()> {
+        // move a node to a new key, edges point at ids so only the key index and the node itself change
+        let id = self.lookup(old_key)?;
+        if old_key == new_key {
+            return Ok(());
+        }
+        if self.contains_key(new_key) {
+            return Err(MapRootError::DuplicateKey(new_key.to_string()));
         }
+        self.index.remove(old_key);
+        self.index.insert(new_key.to_string(), id);
+        self.slot_mut(id).key = new_key.to_string();
+        Ok(())
+    }
 
+    // the arena helpers below expect the id to point at a live node of this structure
 
-        // do the semi-strict test on the node being removed
-        // start by iterating throught the parents and children of the node being removed and check for strictness without including the node being removed
-        // if at any point the strictness is broken return false immediately
+    fn slot(&self, id: NodeId) -> &Node<T> {
+        self.slots[id.index()].as_ref().expect("node id points at an empty slot")
+    }
 
-        // parent check 
-        for parent in parents.iter() {
-            // make sure the parent has at lease one valid child or parent not including the node being removed
-            // use the semi_strict_check_for_one method to check for at least one valid parent or child
-            if !self.semi_strict_check_for_one(parent.rc_clone(), key) {
-                return false
+    fn slot_mut(&mut self, id: NodeId) -> &mut Node<T> {
+        self.slots[id.index()].as_mut().expect("node id points at an empty slot")
+    }
+
+    fn insert_slot(&mut self, key: String, value: T) -> NodeId {
+        let node = Node::new(key.clone(), value);
+        let id = match self.free.pop() {
+            Some(id) => { self.slots[id.index()] = Some(node); id }
+            None => {
+                self.slots.push(Some(node));
+                NodeId((self.slots.len() - 1) as u32)
             }
-        }
+        };
+        self.index.insert(key, id);
+        id
+    }
 
-        // child check
-        for child in children.iter() {
-            // make sure the child has at lease one valid child or parent not including the node being removed
-            // use the semi_strict_check_for_one method to check for at least one valid parent or child
-            if !self.semi_strict_check_for_one(child.rc_clone(), key) {
-                return false
+    fn check_indexes(&self, value: &T, id: Option<NodeId>) -> Result<()> {
+        // the value may not produce an alternate key or unique indexed value another node already holds
+        for (name, index) in self.alt_keys.iter() {
+            if let Some(key) = index.conflict(value, id) {
+                return Err(MapRootError::DuplicateKey(format!("{}:{}", name, key)));
             }
         }
+        for (name, index) in self.indexes.iter() {
+            if let Some(key) = index.conflict(value, id) {
+                return Err(MapRootError::DuplicateKey(format!("{}:{}", name, key)));
+            }
+        }
+        Ok(())
+    }
+
+    fn update_indexes(&mut self, id: NodeId) {
+        let node = self.slots[id.index()].as_ref().expect("node id points at an empty slot");
+        for index in self.alt_keys.values_mut() {
+            index.insert(id, &node.value);
+        }
+        for index in self.indexes.values_mut() {
+            index.insert(id, &node.value);
+        }
+    }
+
+    fn set_value(&mut self, id: NodeId, value: T) -> Result<()> {
+        self.check_indexes(&value, Some(id))?;
+        self.slot_mut(id).value = value;
+        self.update_indexes(id);
+        Ok(())
+    }
+
+    fn connect(&mut self, parent: NodeId, child: NodeId) {
+        // keep both sides of the edge in step
+        self.slot_mut(parent).children.insert(child);
+        self.slot_mut(child).parents.insert(parent);
+        if let Some(reach) = self.reach.as_mut() {
+            reach.link(parent, child);
+        }
+    }
 
-        // if the semi-stric test passes it is safe to remove the node from the structure
-        self.nodes.remove(key);
-        if self.root.is_some() && self.root.as_ref().unwrap().key() == key {
+    fn disconnect(&mut self, parent: NodeId, child: NodeId) {
+        self.slot_mut(parent).children.remove(&child);
+        self.slot_mut(parent).edges.remove(&child);
+        self.slot_mut(child).parents.remove(&parent);
+        if let Some(reach) = self.reach.as_mut() {
+            reach.unlink(&self.slots, parent);
+        }
+    }
+
+    fn detach(&mut self, id: NodeId) -> Node<T> {
+        // take the node out of the arena and drop every edge other nodes have to it
+        let node = self.slots[id.index()].take().expect("node id points at an empty slot");
+        for parent in node.parents.iter().filter(|parent| **parent != id) {
+            self.slot_mut(*parent).children.remove(&id);
+            self.slot_mut(*parent).edges.remove(&id);
+        }
+        for child in node.children.iter().filter(|child| **child != id) {
+            self.slot_mut(*child).parents.remove(&id);
+        }
+        self.index.remove(&node.key);
+        for index in self.alt_keys.values_mut() {
+            index.remove(id);
+        }
+        for index in self.indexes.values_mut() {
+            index.remove(id);
+        }
+        if let Some(reach) = self.reach.as_mut() {
+            reach.remove(&self.slots, id);
+        }
+        self.free.push(id);
+        if self.root == Some(id) {
             self.root = None;
         }
-        if self.nodes.len() == 0 {
+        if self.index.is_empty() {
             self.has_first_node = false;
         }
-        // use borrow checker shenanigans to delete the node
-        let mut other_same_node = node.rc_clone();
-        other_same_node.delete_node();
-        return true
+        node
+    }
 
+    fn resolve(&self, keys: &[String]) -> Result<Vec<NodeId>> {
+        // turn the keys a new node names as neighbours into ids, every one of them has to be in the structure
+        keys.iter().map(|key| self.lookup(key)).collect()
+    }
 
+    fn lookup(&self, key: &str) -> Result<NodeId> {
+        self.id_of(key).ok_or_else(|| MapRootError::NotFound(key.to_string()))
     }
-    pub fn remove_node_by_key(&mut self, key: &str) -> bool {
-        // remove the node from the structure by key
-        // only removes the node from the hashmap and does not actually delete the node 
-        // all relationships will remain the same
-        // return false if the node is not found
-        // return false if this breaks the current strictness of the structure  
 
-        let prim_node = self.find_node_by_key(key);
-        if prim_node.is_none() {
-            return false
+    pub fn dag_test(&self, node: &NewNode<T>) -> Result<()> {
+        // test if the node would be part of a cycle once added to the structure
+        // that happens when one of its children can already reach one of its parents
+        if node.parents.contains(&node.key) || node.children.contains(&node.key) {
+            return Err(MapRootError::Cycle(vec![node.key.clone(), node.key.clone()]));
         }
+        let parents: IdSet = self.resolve(&node.parents)?.into_iter().collect();
+        let children = self.resolve(&node.children)?;
 
-        let mut node: NodeRef<T> = prim_node.unwrap(); 
-        if self.mode == "un-strict" {
-            self.nodes.remove(key);
-            if self.root.is_some() && self.root.as_ref().unwrap().key() == key {
-                self.root = None;
+        if let Some(path) = self.child_path(&children, &parents) {
+            let mut cycle = vec![node.key.clone()];
+            cycle.extend(path.into_iter().map(|id| self.slot(id).key.clone()));
+            cycle.push(node.key.clone());
+            return Err(MapRootError::Cycle(cycle));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn child_path(&self, from: &[NodeId], to: &IdSet) -> Option<Vec<NodeId>> {
+        // breadth first search along the child edges from any of the starting nodes to any of the targets
+        // returns the ids on the path including both ends
+        if to.is_empty() {
+            return None
+        }
+        let mut came_from: IdMap<NodeId> = IdMap::default();
+        let mut visited: IdSet = from.iter().copied().collect();
+        let mut queue: VecDeque<NodeId> = from.iter().copied().collect();
+
+        while let Some(current) = queue.pop_front() {
+            if to.contains(&current) {
+                // walk the came_from map back to the start to rebuild the path
+                let mut path = vec![current];
+                let mut step = current;
+                while let Some(previous) = came_from.get(&step) {
+                    path.push(*previous);
+                    step = *previous;
+                }
+                path.reverse();
+                return Some(path);
             }
-            if self.nodes.len() == 0 {
-                self.has_first_node = false;
+            for child in self.slot(current).children.iter() {
+                if visited.insert(*child) {
+                    came_from.insert(*child, current);
+                    queue.push_back(*child);
+                }
             }
-            return true
         }
+        None
+    }
+}
 
-        let parents: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.parents();
-        let children: std::cell::Ref<'_, std::collections::HashSet<NodeRef<T>>> = node.children(); 
+impl<T: Clone + Eq + Serialize> Structure<T> {
+    pub fn new<P: StructurePolicy<T> + 'static>(root: Option<NewNode<T>>, policy: P) -> Self {
+        Self::with_policy(root, Box::new(policy))
+    }
 
-        if parents.len() == 0 && children.len() == 0 && !self.has_first_node {
-            return false
-        } else if parents.len() == 0 && children.len() == 0 && self.has_first_node {
-            return true
+    pub fn with_policy(root: Option<NewNode<T>>, policy: Box<dyn StructurePolicy<T>>) -> Self {
+        // the root cannot have edges yet, any keys it names are ignored
+        let mut structure = Structure {
+            root: None,
+            slots: Vec::new(),
+            free: Vec::new(),
+            index: BTreeMap::new(),
+            policy,
+            alt_keys: HashMap::new(),
+            indexes: HashMap::new(),
+            reach: None,
+            has_first_node: false,
+        };
+
+        if let Some(root) = root {
+            let id = structure.insert_slot(root.key, root.value); // Insert root node
+            structure.root = Some(id);
+            structure.has_first_node = true;
         }
+        structure
+    }
 
+    // what may be added, removed or linked is up to the policy of the structure, see policy.rs for the built in modes
+
+    pub fn serialize_related_ids(&self) -> Result<Vec<u8>>{
+        // in this function I am seraialing all the keys of the given hashmap so that I can rebuild by grabbing all the nodes by key allowing for rebuild
+        let keys: Vec<&str> = self.keys().collect(); 
 
-        for parent in parents.iter() {
-            if !self.semi_strict_check_for_one(parent.rc_clone(), key) {
-                return false
+        // seraialize with bincode and return the vector
+        encode(&keys, "structure keys")
+
+    }
+
+    pub fn serialize_header(&self) -> Result<Vec<u8>> {
+        let header = StructureHeader {
+            version: STRUCTURE_FORMAT_VERSION,
+            mode: self.mode().to_string(),
+            root: self.root().map(|root| root.key.clone()),
+            has_first_node: self.has_first_node,
+        };
+        encode(&header, "structure header")
+    }
+
+    pub fn serialize_node(&self, key: &str) -> Result<Vec<u8>> {
+        // the record for a single node, with its edges written as the keys of its neighbours
+        self.serialize_slot(self.lookup(key)?)
+    }
+
+    pub fn serialize_related_nodes(&self) -> Result<Vec<Vec<u8>>>{
+        // iterate through the arena to get the vectors for each node
+        self.nodes().map(|(id, _)| self.serialize_slot(id)).collect()
+    }
+
+    fn serialize_slot(&self, id: NodeId) -> Result<Vec<u8>> {
+        let node = self.slot(id);
+        let parents: Vec<&str> = node.parents.iter().map(|parent| self.slot(*parent).key.as_str()).collect();
+        let children: Vec<&str> = node.children.iter().map(|child| self.slot(*child).key.as_str()).collect();
+        let edges: Vec<(&str, Edge)> = node.edges.iter().map(|(child, edge)| (self.slot(*child).key.as_str(), edge.clone())).collect();
+        node::serialize_node(&node.key, &node.value, &parents, &children, &edges)
+    }
+
+
+    pub fn delete_node_by_key(&mut self, key: &str) -> Result<()> {
+        // remove a node from the structure by key and free it
+        // its edges are dropped from its parents and children and the root is cleared if it was the root
+        // fails if the node is not found or removing it breaks the current strictness of the structure
+        let id = self.lookup(key)?;
+        self.policy.before_remove(self, id)?;
+        self.detach(id);
+        Ok(())
+    }
+
+    pub fn remove_node_by_key(&mut self, key: &str) -> Result<NewNode<T>> {
+        // remove the node from the structure by key but hand it back instead of dropping it
+        // the returned node still names its old parents and children so it can be added back later
+        // fails if the node is not found or removing it breaks the current strictness of the structure
+        let id = self.lookup(key)?;
+        self.policy.before_remove(self, id)?;
+        let parents: Vec<String> = self.slot(id).parents.iter().filter(|parent| **parent != id).map(|parent| self.slot(*parent).key.clone()).collect();
+        let children: Vec<String> = self.slot(id).children.iter().filter(|child| **child != id).map(|child| self.slot(*child).key.clone()).collect();
+        let edges: Vec<(String, Edge)> = self.slot(id).edges.iter().filter(|(child, _)| **child != id).map(|(child, edge)| (self.slot(*child).key.clone(), edge.clone())).collect();
+        let node = self.detach(id);
+        Ok(NewNode { key: node.key, value: node.value, parents, children, edges })
+    }
+
+    // there are three ways to put a node under a key
+    //     insert   the key must be new, the node comes in with the edges it names
+    //     upsert   insert if the key is new, otherwise only the value changes and the edges stay as they are
+    //     replace  the key must exist, the node takes over the id and edges of the old one plus the edges it names
+    // add_node is insert
+
+    pub fn add_node(&mut self, node: NewNode<T>) -> Result<NodeId> {
+        self.insert(node)
+    }
+
+    pub fn insert(&mut self, node: NewNode<T>) -> Result<NodeId> {
+        if self.contains_key(&node.key) {
+            return Err(MapRootError::DuplicateKey(node.key))
+        }
+        let (parents, children) = self.resolve_neighbours(&node)?;
+        let edges = self.resolve_edges(&node)?;
+        self.check_indexes(&node.value, None)?;
+        self.policy.before_add(self, &node)?;
+
+        let first = self.is_empty();
+        let id = self.insert_slot(node.key, node.value);
+        self.update_indexes(id);
+        if first && self.root.is_none() && self.policy.roots_first_node() {
+            self.root = Some(id);
+        }
+        for parent in parents {
+            self.connect(parent, id);
+        }
+        for child in children {
+            self.connect(id, child);
+        }
+        self.slot_mut(id).edges.extend(edges);
+        self.has_first_node = true;
+        Ok(id)
+    }
+
+    pub fn upsert(&mut self, node: NewNode<T>) -> Result<NodeId> {
+        // the parents and children the node names are only used when it is inserted
+        match self.id_of(&node.key) {
+            Some(id) => {
+                self.set_value(id, node.value)?;
+                Ok(id)
             }
+            None => self.insert(node),
         }
+    }
+
+    pub fn replace(&mut self, node: NewNode<T>) -> Result<NodeId> {
+        // every edge the node names that the old one did not have goes through the policy like a link,
+        // if one is refused the edges added so far are dropped again and the old node is left as it was
+        let id = self.lookup(&node.key)?;
+        let (parents, children) = self.resolve_neighbours(&node)?;
+        let labels = self.resolve_edges(&node)?;
+        self.check_indexes(&node.value, Some(id))?;
+        let edges = parents.into_iter().map(|parent| (parent, id)).chain(children.into_iter().map(|child| (id, child)));
 
-        for child in children.iter() {
-            if !self.semi_strict_check_for_one(child.rc_clone(), key) {
-                return false
+        let mut added: Vec<(NodeId, NodeId)> = Vec::new();
+        for (parent, child) in edges {
+            if self.slot(parent).has_child(child) {
+                continue;
             }
-        }   
+            if let Err(e) = self.policy.before_link(self, parent, child) {
+                for (parent, child) in added {
+                    self.disconnect(parent, child);
+                }
+                return Err(e);
+            }
+            self.connect(parent, child);
+            added.push((parent, child));
+        }
+        self.set_value(id, node.value)?;
+        self.slot_mut(id).edges.extend(labels);
+        Ok(id)
+    }
 
-        self.nodes.remove(key); 
-        if self.root.is_some() && self.root.as_ref().unwrap().key() == key {
-            self.root = None;
+    fn resolve_neighbours(&self, node: &NewNode<T>) -> Result<(Vec<NodeId>, Vec<NodeId>)> {
+        // every parent and child the node names has to be in the structure already and can not be the node itself
+        if node.parents.iter().chain(node.children.iter()).any(|key| *key == node.key) {
+            return Err(MapRootError::Cycle(vec![node.key.clone(), node.key.clone()]))
         }
-        if self.nodes.len() == 0 {
-            self.has_first_node = false;
+        Ok((self.resolve(&node.parents)?, self.resolve(&node.children)?))
+    }
+
+    fn resolve_edges(&self, node: &NewNode<T>) -> Result<Vec<(NodeId, Edge)>> {
+        // a label can only go on an edge to one of the children the node names
+        node.edges
+            .iter()
+            .map(|(child, edge)| match node.children.contains(child) {
+                true => Ok((self.lookup(child)?, edge.clone())),
+                false => Err(MapRootError::EdgeNotFound { parent: node.key.clone(), child: child.clone() }),
+            })
+            .collect()
+    }
+
+    pub fn link(&mut self, parent_key: &str, child_key: &str) -> Result<()> {
+        // create a parent -> child edge between two nodes of the structure if the policy allows it
+        // linking an edge that is already there changes nothing and always succeeds
+        let parent = self.lookup(parent_key)?;
+        let child = self.lookup(child_key)?;
+        if self.slot(parent).has_child(child) {
+            return Ok(());
         }
+        self.policy.before_link(self, parent, child)?;
+
+        self.connect(parent, child);
+        Ok(())
+    }
 
-        return true
+    pub fn link_with(&mut self, parent_key: &str, child_key: &str, edge: Edge) -> Result<()> {
+        // link like link does and put the label on the edge, an edge that is already there only has its label replaced
+        self.link(parent_key, child_key)?;
+        let (parent, child) = (self.lookup(parent_key)?, self.lookup(child_key)?);
+        self.slot_mut(parent).edges.insert(child, edge);
+        Ok(())
+    }
 
+    pub fn unlink(&mut self, parent_key: &str, child_key: &str) -> Result<()> {
+        // drop the parent -> child edge between two nodes of the structure if the policy allows it
+        // both nodes stay in the structure, only the edge between them goes
+        let parent = self.lookup(parent_key)?;
+        let child = self.lookup(child_key)?;
+        if !self.slot(parent).has_child(child) {
+            return Err(MapRootError::EdgeNotFound { parent: parent_key.to_string(), child: child_key.to_string() });
+        }
+        self.policy.before_unlink(self, parent, child)?;
 
+        self.disconnect(parent, child);
+        Ok(())
     }
 
-    pub fn find_node_by_key(&self, key: &str) -> Option<NodeRef<T>> {
-        // find a node in the structure by key using the hashmap 
-        // return the reference to the node if found
-        // return None if not found 
-        self.nodes.get(key).map(|node| node.rc_clone())
+    pub fn move_child(&mut self, child_key: &str, from_key: &str, to_key: &str) -> Result<()> {
+        // swap the from -> child edge for a to -> child edge in one change the policy judges as a whole,
+        // which is how a node of a tree gets a new parent, the label of the old edge moves along with it
+        let child = self.lookup(child_key)?;
+        let from = self.lookup(from_key)?;
+        let to = self.lookup(to_key)?;
+        if !self.slot(from).has_child(child) {
+            return Err(MapRootError::EdgeNotFound { parent: from_key.to_string(), child: child_key.to_string() });
+        }
+        if to == child {
+            return Err(MapRootError::Cycle(vec![child_key.to_string(), child_key.to_string()]));
+        }
+        if to == from {
+            return Ok(());
+        }
+        self.policy.before_move(self, child, from, to)?;
 
+        let edge = self.slot_mut(from).edges.remove(&child);
+        if !self.slot(to).has_child(child) {
+            self.connect(to, child);
+            if let Some(edge) = edge {
+                self.slot_mut(to).edges.insert(child, edge);
+            }
+        }
+        self.disconnect(from, child);
+        Ok(())
     }
+}
+
+impl<T: Clone + Eq + Serialize + DeserializeOwned + 'static> Structure<T> {
+    pub fn from_serialized<N: AsRef<[u8]>>(ids: &[u8], nodes: &[N], policy: Box<dyn StructurePolicy<T>>) -> Result<Self> {
+        // rebuild a structure from the output of serialize_related_ids and serialize_related_nodes
+        // first pass decodes every node record, second pass relinks the parents and children by key
+        // edges to nodes that were not part of the saved structure are dropped
+        let keys: Vec<String> = decode(ids, "structure ids")?;
+        let keys: HashSet<String> = keys.into_iter().collect();
+        let mut structure: Structure<T> = Structure::with_policy(None, policy);
+        let mut links: Vec<(NodeId, Vec<String>, Vec<String>)> = Vec::new();
+        let mut labels: Vec<(NodeId, Vec<(String, Edge)>)> = Vec::new();
 
-    pub fn add_node(&mut self, node: NodeRef<T>) -> Result<NodeRef<T>, bool> {
-        // depending on the mode use the correct add method
-        match self.mode.as_str() {
-            "semi-strict" => self.semi_strict_add(node),
-            "un-strict" => self.un_strict_add(node),
-            _ => Err(false),
+        for record in nodes.iter() {
+            let node: NewNode<T> = NewNode::deserialize_node(record.as_ref())?;
+            if !keys.contains(&node.key) {
+                return Err(FormatError::Invalid(format!("node {} is not listed in the structure ids", node.key)).into());
+            }
+            if structure.contains_key(&node.key) {
+                return Err(FormatError::Invalid(format!("node {} has more than one record", node.key)).into());
+            }
+            let id = structure.insert_slot(node.key, node.value);
+            links.push((id, node.parents, node.children));
+            labels.push((id, node.edges));
+        }
+        if let Some(missing) = keys.iter().find(|key| !structure.contains_key(key)) {
+            return Err(FormatError::Invalid(format!("no node record for {}", missing)).into());
+        }
+
+        for (id, parents, children) in links {
+            let parents: Vec<NodeId> = parents.iter().filter_map(|parent| structure.id_of(parent)).collect();
+            let children: Vec<NodeId> = children.iter().filter_map(|child| structure.id_of(child)).collect();
+            for parent in parents {
+                structure.connect(parent, id);
+            }
+            for child in children {
+                structure.connect(id, child);
+            }
+        }
+        // labels go on last, once every edge they could belong to is in place
+        for (id, edges) in labels {
+            for (child, edge) in edges {
+                if let Some(child) = structure.id_of(&child).filter(|child| structure.slot(id).has_child(*child)) {
+                    structure.slot_mut(id).edges.insert(child, edge);
+                }
+            }
         }
+
+        structure.has_first_node = !structure.is_empty();
+        Ok(structure)
     }
 
-    fn semi_strict_add(&mut self, node: NodeRef<T>) -> Result<NodeRef<T>, bool> {
-        // perform a semi-strict test on the node to see if it can be added to the structure
-        if self.semi_strict_test(node.rc_clone()) {
-            self.nodes.insert(node.key(), node.rc_clone());
-            self.has_first_node = true;
-            return Ok(node)
-        } else {
-            return Err(false)
+}
+
+// snapshots name the value type in their header, so only value types with a ValueTag can be saved or loaded
+
+impl<T: Clone + Eq + Serialize + ValueTag> Structure<T> {
+    pub fn serialize_snapshot(&self) -> Result<Vec<u8>> {
+        // the full structure in one container, the structure header record goes first so it can be checked
+        // before anything else is decoded, then the ids and one record per node
+        let mut snapshot: Vec<u8> = Vec::new();
+        format::write_header(&mut snapshot, FileKind::Structure, &format::value_type_tag::<T>());
+        format::write_record(&mut snapshot, &self.serialize_header()?)?;
+        format::write_record(&mut snapshot, &self.serialize_related_ids()?)?;
+        for node in self.serialize_related_nodes()? {
+            format::write_record(&mut snapshot, &node)?;
         }
+        Ok(snapshot)
     }
+}
 
-    fn un_strict_add(&mut self, node: NodeRef<T>) -> Result<NodeRef<T>, bool> {
-        // simply add the node to the structure
-        self.nodes.insert(node.key(), node.rc_clone());
-        self.has_first_node = true; 
-        return Ok(node)
+impl<T: Clone + Eq + Serialize + DeserializeOwned + ValueTag + 'static> Structure<T> {
+    pub fn from_snapshot(data: &[u8]) -> Result<Self> {
+        Self::from_snapshot_with(data, &PolicyRegistry::default())
     }
 
-    pub fn semi_strict_test (&mut self, node: NodeRef<T>) -> bool  {
-        // test if the node has at least one parent or child in the structure
-        let parents = node.parents();
-        let children = node.children();
+    pub fn from_snapshot_with(data: &[u8], registry: &PolicyRegistry<T>) -> Result<Self> {
+        // rebuild a structure from the output of serialize_snapshot with the same mode and root it was saved with
+        // the mode is looked up in the registry, so a structure using a custom policy needs it registered first
+        let offset = format::read_header(data, FileKind::Structure, &format::value_type_tag::<T>())?;
+        let (header, offset) = format::read_record(data, offset, "structure header")?;
+        let header: StructureHeader = decode(header, "structure header")?;
+        if header.version != STRUCTURE_FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion { what: "structure format".to_string(), version: header.version }.into());
+        }
+        let (ids, mut offset) = format::read_record(data, offset, "structure ids")?;
+        let mut nodes: Vec<&[u8]> = Vec::new();
+        while offset < data.len() {
+            let (node, next) = format::read_record(data, offset, "node record")?;
+            nodes.push(node);
+            offset = next;
+        }
 
-        if parents.len() == 0 && children.len() == 0 && !self.has_first_node {
-            return false
-        } else if parents.len() == 0 && children.len() == 0 && self.has_first_node {
-            return true
+        let mut structure = Structure::from_serialized(ids, &nodes, registry.create(&header.mode)?)?;
+        // the snapshot was valid when it was taken, so the root is restored without asking the policy again
+        if let Some(root_key) = header.root {
+            let Some(root) = structure.id_of(&root_key) else {
+                return Err(FormatError::Invalid(format!("root node {} is missing from the snapshot", root_key)).into());
+            };
+            structure.root = Some(root);
         }
+        structure.has_first_node = header.has_first_node;
+        Ok(structure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{Dag, SemiStrict, Tree, UnStrict};
 
-        // iterate through the parents hashset and if the parent is in the structure return true
+    fn keys<T: Clone>(page: &KeyPage<'_, T>) -> Vec<String> {
+        page.nodes.iter().map(|node| node.key.clone()).collect()
+    }
 
-        for parent in parents.iter() {
-            if self.nodes.contains_key(&parent.key()) {
-                return true
-            }           
+    #[test]
+    fn pages_walk_the_whole_range_and_refuse_a_zero_limit() {
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        for key in ["a1", "a2", "a3", "b1"] {
+            structure.add_node(NewNode::new(key.to_string(), 0)).unwrap();
         }
 
-        // iterate through the children hashset and if the child is in the structure return true
-        for child in children.iter() {
-            if self.nodes.contains_key(&child.key()) {
-                return true
-            }
+        let first = structure.scan_prefix_page("a", None, 2).unwrap();
+        assert_eq!(keys(&first), ["a1", "a2"]);
+        let second = structure.scan_prefix_page("a", first.next.as_deref(), 2).unwrap();
+        assert_eq!(keys(&second), ["a3"]);
+        assert_eq!(second.next, None);
+
+        let page = structure.range_page("a2".."b2", Some("a2"), 5).unwrap();
+        assert_eq!(keys(&page), ["a3", "b1"]);
+        assert!(matches!(structure.range_page("a1".., None, 0), Err(MapRootError::ZeroLimit)));
+        assert!(matches!(structure.scan_prefix_page("a", Some("a1"), 0), Err(MapRootError::ZeroLimit)));
+    }
+
+    #[test]
+    fn snapshot_of_another_value_type_is_a_type_mismatch_not_corruption() {
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        structure.add_node(NewNode::new("a".to_string(), 1)).unwrap();
+        let snapshot = structure.serialize_snapshot().unwrap();
+        assert!(matches!(
+            Structure::<String>::from_snapshot(&snapshot),
+            Err(MapRootError::TypeMismatch { expected, found }) if expected == "string" && found == "u32"
+        ));
+    }
+
+    fn with_parents(key: &str, value: u32, parents: &[&str]) -> NewNode<u32> {
+        let mut node = NewNode::new(key.to_string(), value);
+        for parent in parents {
+            node.add_parent(parent);
         }
-        
-        // if nothing has been found return false
-        return false
+        node
     }
 
+    #[test]
+    fn insert_upsert_and_replace_only_touch_what_they_promise() {
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        structure.insert(with_parents("a", 1, &[])).unwrap();
+        structure.insert(with_parents("b", 2, &["a"])).unwrap();
+        assert!(matches!(structure.insert(with_parents("b", 3, &[])), Err(MapRootError::DuplicateKey(key)) if key == "b"));
+        assert!(matches!(structure.insert(with_parents("c", 3, &["missing"])), Err(MapRootError::NotFound(key)) if key == "missing"));
+        assert!(!structure.contains_key("c"));
 
-}
+        // an existing node only gets the new value, the neighbours it names are for an insert
+        let b = structure.id_of("b").unwrap();
+        assert_eq!(structure.upsert(with_parents("b", 20, &["missing"])).unwrap(), b);
+        assert_eq!(structure.find_node_by_key("b").unwrap().value, 20);
+        structure.upsert(with_parents("c", 3, &["b"])).unwrap();
+        assert!(structure.has_child_by_key("b", "c"));
+
+        // replace keeps the id and the edges it had and adds the ones it names
+        assert_eq!(structure.replace(with_parents("c", 30, &["a"])).unwrap(), structure.id_of("c").unwrap());
+        assert_eq!(structure.find_node_by_key("c").unwrap().value, 30);
+        assert!(structure.has_child_by_key("a", "c") && structure.has_child_by_key("b", "c"));
+        assert!(matches!(structure.replace(with_parents("d", 4, &[])), Err(MapRootError::NotFound(key)) if key == "d"));
+    }
+
+    #[test]
+    fn refused_replace_drops_the_edges_it_already_added() {
+        // a -> b, c stands alone
+        let mut structure: Structure<u32> = Structure::new(None, Dag);
+        structure.insert(with_parents("a", 1, &[])).unwrap();
+        structure.insert(with_parents("b", 2, &["a"])).unwrap();
+        structure.insert(with_parents("c", 3, &[])).unwrap();
+
+        // b -> c goes in first, then c -> b would close a cycle
+        let mut node = with_parents("c", 30, &["b"]);
+        node.add_child("b");
+        assert!(matches!(structure.replace(node), Err(MapRootError::Cycle(_))));
+        assert!(!structure.has_child_by_key("b", "c"));
+        assert!(!structure.has_child_by_key("c", "b"));
+        assert_eq!(structure.find_node_by_key("c").unwrap().value, 3);
+    }
+
+    #[test]
+    fn rename_moves_the_key_and_keeps_everything_hanging_off_the_node() {
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        structure.insert(with_parents("a", 1, &[])).unwrap();
+        structure.insert(with_parents("b", 2, &["a"])).unwrap();
+        structure.insert(with_parents("c", 3, &["b"])).unwrap();
+        structure.link_with("a", "b", Edge::new("owns")).unwrap();
+        structure.set_root(Some("b")).unwrap();
+        structure.register_alt_key("value", |value: &u32| Some(value.to_string())).unwrap();
+        let id = structure.id_of("b").unwrap();
+
+        structure.rename("b", "x").unwrap();
+        assert_eq!(structure.id_of("x"), Some(id));
+        assert!(!structure.contains_key("b"));
+        assert_eq!(structure.keys().collect::<Vec<_>>(), ["a", "c", "x"]);
+        assert_eq!(structure.edge("a", "x").unwrap().unwrap().label, "owns");
+        assert!(structure.has_child_by_key("x", "c"));
+        assert_eq!(structure.root().unwrap().key, "x");
+        assert_eq!(structure.find_node_by_alt_key("value", "2").unwrap().unwrap().key, "x");
+
+        assert!(matches!(structure.rename("x", "c"), Err(MapRootError::DuplicateKey(key)) if key == "c"));
+        assert!(matches!(structure.rename("b", "y"), Err(MapRootError::NotFound(key)) if key == "b"));
+        structure.rename("x", "x").unwrap();
+        assert_eq!(structure.id_of("x"), Some(id));
+    }
+
+    fn edges(structure: &Structure<u32>) -> Vec<(String, String)> {
+        let mut edges: Vec<(String, String)> = structure
+            .nodes()
+            .flat_map(|(_, node)| structure.children(&node.key).into_iter().map(|child| (node.key.clone(), child.key.clone())))
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    #[test]
+    fn serialized_ids_and_nodes_rebuild_the_same_graph() {
+        // a cycle and a second path, so relinking by key has to cope with edges to nodes read later
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            structure.insert(with_parents(key, value, &[])).unwrap();
+        }
+        for (parent, child) in [("a", "b"), ("b", "c"), ("c", "a"), ("a", "c")] {
+            structure.link(parent, child).unwrap();
+        }
+        let ids = structure.serialize_related_ids().unwrap();
+        let nodes = structure.serialize_related_nodes().unwrap();
+
+        let rebuilt = Structure::<u32>::from_serialized(&ids, &nodes, Box::new(UnStrict)).unwrap();
+        assert_eq!(rebuilt.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(rebuilt.find_node_by_key("c").unwrap().value, 3);
+        assert_eq!(edges(&rebuilt), edges(&structure));
 
+        // every record has to be listed in the ids exactly once, and every id needs its record
+        let invalid = |result: Result<Structure<u32>>| matches!(result, Err(MapRootError::Corrupt(FormatError::Invalid(_))));
+        assert!(invalid(Structure::from_serialized(&ids, &nodes[..2], Box::new(UnStrict))));
+        let doubled: Vec<&Vec<u8>> = nodes.iter().chain(nodes.iter().take(1)).collect();
+        assert!(invalid(Structure::from_serialized(&ids, &doubled, Box::new(UnStrict))));
+        let fewer_ids = encode(&vec!["a", "b"], "structure ids").unwrap();
+        assert!(invalid(Structure::from_serialized(&fewer_ids, &nodes, Box::new(UnStrict))));
+    }
+
+    #[test]
+    fn snapshot_header_brings_back_the_mode_root_and_first_node() {
+        let mut tree: Structure<u32> = Structure::new(None, Tree);
+        tree.insert(with_parents("r", 1, &[])).unwrap();
+        tree.insert(with_parents("a", 2, &["r"])).unwrap();
+        let mut loaded = Structure::<u32>::from_snapshot(&tree.serialize_snapshot().unwrap()).unwrap();
+        assert_eq!(loaded.mode(), "tree");
+        assert_eq!(loaded.root().unwrap().key, "r");
+        assert!(matches!(loaded.insert(with_parents("x", 3, &[])), Err(MapRootError::StrictnessViolation { .. })));
+        loaded.insert(with_parents("b", 3, &["a"])).unwrap();
+
+        // a semi-strict structure without a root still knows its first node is taken
+        let mut semi: Structure<u32> = Structure::new(None, SemiStrict);
+        semi.insert(with_parents("a", 1, &[])).unwrap();
+        let mut loaded = Structure::<u32>::from_snapshot(&semi.serialize_snapshot().unwrap()).unwrap();
+        assert_eq!(loaded.mode(), "semi-strict");
+        assert!(loaded.root().is_none());
+        assert!(loaded.has_first_node);
+        assert!(matches!(loaded.insert(with_parents("b", 2, &[])), Err(MapRootError::StrictnessViolation { .. })));
+
+        // a header from another format version is refused before any node is read
+        let header = StructureHeader { version: STRUCTURE_FORMAT_VERSION + 1, mode: "tree".to_string(), root: None, has_first_node: false };
+        let mut snapshot = Vec::new();
+        format::write_header(&mut snapshot, FileKind::Structure, &format::value_type_tag::<u32>());
+        format::write_record(&mut snapshot, &encode(&header, "structure header").unwrap()).unwrap();
+        assert!(matches!(
+            Structure::<u32>::from_snapshot(&snapshot),
+            Err(MapRootError::Corrupt(FormatError::UnsupportedVersion { version, .. })) if version == STRUCTURE_FORMAT_VERSION + 1
+        ));
+    }
 
+    #[test]
+    fn snapshot_with_a_custom_mode_needs_it_registered() {
+        struct Frozen;
+        impl StructurePolicy<u32> for Frozen {
+            fn name(&self) -> &str {
+                "frozen"
+            }
+
+            fn before_add(&self, _structure: &Structure<u32>, node: &NewNode<u32>) -> Result<()> {
+                Err(MapRootError::StrictnessViolation { key: node.key.clone(), mode: "frozen".to_string(), offending_neighbors: Vec::new() })
+            }
+        }
 
+        let structure: Structure<u32> = Structure::new(None, Frozen);
+        let snapshot = structure.serialize_snapshot().unwrap();
+        assert!(matches!(Structure::<u32>::from_snapshot(&snapshot), Err(MapRootError::UnknownMode(mode)) if mode == "frozen"));
+        let mut registry = PolicyRegistry::default();
+        registry.register("frozen", || Frozen);
+        let mut loaded = Structure::<u32>::from_snapshot_with(&snapshot, &registry).unwrap();
+        assert!(matches!(loaded.insert(with_parents("a", 1, &[])), Err(MapRootError::StrictnessViolation { .. })));
+    }
+
+    #[test]
+    fn labels_filter_neighbours_and_survive_a_snapshot() {
+        // p -> a owns, p -> b owns, p -> c plain, q -> a follows
+        let mut structure: Structure<u32> = Structure::new(None, UnStrict);
+        for key in ["p", "q", "a", "b", "c"] {
+            structure.insert(with_parents(key, 0, &[])).unwrap();
+        }
+        structure.link_with("p", "a", Edge::new("owns").with_payload(&7u64).unwrap()).unwrap();
+        structure.link_with("p", "b", Edge::new("owns")).unwrap();
+        structure.link("p", "c").unwrap();
+        structure.link_with("q", "a", Edge::new("follows")).unwrap();
+
+        let keys = |nodes: Vec<&Node<u32>>| {
+            let mut keys: Vec<String> = nodes.into_iter().map(|node| node.key.clone()).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(structure.children_labeled("p", "owns")), ["a", "b"]);
+        assert_eq!(keys(structure.parents_labeled("a", "owns")), ["p"]);
+        assert_eq!(keys(structure.parents_labeled("a", "follows")), ["q"]);
+        assert!(structure.children_labeled("p", "follows").is_empty());
+        assert!(structure.edge("p", "c").unwrap().is_none());
+        assert!(structure.children_labeled("missing", "owns").is_empty());
+
+        let mut loaded = Structure::<u32>::from_snapshot(&structure.serialize_snapshot().unwrap()).unwrap();
+        assert_eq!(keys(loaded.children_labeled("p", "owns")), ["a", "b"]);
+        assert_eq!(loaded.edge("p", "a").unwrap().unwrap().payload::<u64>().unwrap(), Some(7));
+        assert!(loaded.edge("p", "c").unwrap().is_none());
+
+        // relabeling replaces the label, unlinking drops it with the edge
+        loaded.link_with("p", "b", Edge::new("lent")).unwrap();
+        assert_eq!(keys(loaded.children_labeled("p", "owns")), ["a"]);
+        loaded.unlink("q", "a").unwrap();
+        assert!(loaded.parents_labeled("a", "follows").is_empty());
+    }
+}
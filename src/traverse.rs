@@ -0,0 +1,230 @@
+// lazy walks over the ancestors or descendants of a node
+// every node is returned once even when several paths lead to it, so diamonds and cycles are safe to walk
+// the node the walk starts from is not part of it
+
+use crate::node::{IdMap, IdSet, Node, NodeId};
+use crate::structure::Structure;
+use std::collections::VecDeque;
+
+// which edges a walk follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Children,
+    Parents,
+    Either, // edges are followed both ways
+}
+
+pub(crate) fn neighbours<T: Clone>(node: &Node<T>, direction: Direction) -> impl Iterator<Item = NodeId> + '_ {
+    let (children, parents) = match direction {
+        Direction::Children => (Some(&node.children), None),
+        Direction::Parents => (None, Some(&node.parents)),
+        Direction::Either => (Some(&node.children), Some(&node.parents)),
+    };
+    children.into_iter().chain(parents).flatten().copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    BreadthFirst, // nearest nodes first, each node comes out at its shortest distance from the start
+    DepthFirst,
+}
+
+type WalkFilter<'a, T> = Box<dyn Fn(&T) -> bool + 'a>;
+
+pub struct Walk<'a, T: Clone> {
+    structure: &'a Structure<T>,
+    direction: Direction,
+    order: Order,
+    max_depth: Option<usize>,
+    filter: Option<WalkFilter<'a, T>>,
+    frontier: VecDeque<(NodeId, usize)>, // nodes waiting to be visited with their distance from the start
+    depths: IdMap<usize>, // the shortest distance each node has been expanded at so far
+    returned: IdSet,
+}
+
+impl<'a, T: Clone> Walk<'a, T> {
+    pub(crate) fn new(structure: &'a Structure<T>, start: NodeId, direction: Direction) -> Self {
+        let mut walk = Walk {
+            structure,
+            direction,
+            order: Order::BreadthFirst,
+            max_depth: None,
+            filter: None,
+            frontier: VecDeque::new(),
+            depths: IdMap::from_iter([(start, 0)]),
+            returned: IdSet::from_iter([start]),
+        };
+        walk.expand(start, 0);
+        walk
+    }
+
+    pub fn depth_first(mut self) -> Self {
+        self.order = Order::DepthFirst;
+        self
+    }
+
+    pub fn breadth_first(mut self) -> Self {
+        self.order = Order::BreadthFirst;
+        self
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        // stop this many edges away from the start, the direct neighbours are at depth 1
+        self.max_depth = Some(depth);
+        self.frontier.retain(|(_, d)| *d <= depth);
+        self
+    }
+
+    pub fn filter<F: Fn(&T) -> bool + 'a>(mut self, filter: F) -> Self {
+        // only nodes whose value passes are returned, the walk still goes through the others
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    fn expand(&mut self, id: NodeId, depth: usize) {
+        if self.max_depth.is_some_and(|max| depth >= max) {
+            return;
+        }
+        let Some(node) = self.structure.node(id) else { return };
+        for neighbour in neighbours(node, self.direction).filter(|neighbour| self.depths.get(neighbour).is_none_or(|best| *best > depth + 1)) {
+            self.frontier.push_back((neighbour, depth + 1));
+        }
+    }
+}
+
+impl<'a, T: Clone> Iterator for Walk<'a, T> {
+    type Item = &'a Node<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (id, depth) = match self.order {
+                Order::BreadthFirst => self.frontier.pop_front()?,
+                Order::DepthFirst => self.frontier.pop_back()?,
+            };
+            // a node can be queued from several paths before it is visited
+            // with a depth limit a depth first walk can reach a node the long way round first, so it is expanded
+            // again when a shorter path turns up or the nodes just past the limit of the long path are missed
+            // it is only returned the first time
+            if let Some(best) = self.depths.get(&id) {
+                if self.max_depth.is_none() || *best <= depth {
+                    continue;
+                }
+            }
+            self.depths.insert(id, depth);
+            self.expand(id, depth);
+            if !self.returned.insert(id) {
+                continue;
+            }
+            let Some(node) = self.structure.node(id) else { continue };
+            if self.filter.as_ref().is_none_or(|filter| filter(&node.value)) {
+                return Some(node);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Direction;
+    use crate::error::MapRootError;
+    use crate::node::NewNode;
+    use crate::policy::UnStrict;
+    use crate::structure::Structure;
+
+    fn structure(edges: &[(&str, &str)]) -> Structure<u32> {
+        let mut structure = Structure::new(None, UnStrict);
+        for (parent, child) in edges {
+            for key in [parent, child] {
+                if structure.find_node_by_key(key).is_none() {
+                    structure.add_node(NewNode::new(key.to_string(), 0)).unwrap();
+                }
+            }
+            structure.link(parent, child).unwrap();
+        }
+        structure
+    }
+
+    fn sorted<'a>(keys: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+        let mut keys: Vec<&str> = keys.collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn depth_first_with_a_limit_finds_what_breadth_first_finds() {
+        // C is two edges away through B but one edge away directly, D hangs off C
+        // the nodes are added in both orders so the long way round is tried first in one of them whatever the set order
+        for edges in [[("A", "B"), ("A", "C"), ("B", "C"), ("C", "D")], [("A", "C"), ("A", "B"), ("B", "C"), ("C", "D")]] {
+            let structure = structure(&edges);
+            for depth in 1..=3 {
+                let breadth = sorted(structure.descendants("A").unwrap().max_depth(depth).map(|node| node.key.as_str()));
+                let deep = sorted(structure.descendants("A").unwrap().depth_first().max_depth(depth).map(|node| node.key.as_str()));
+                assert_eq!(deep, breadth, "max depth {}", depth);
+            }
+            let deep = sorted(structure.descendants("A").unwrap().depth_first().max_depth(2).map(|node| node.key.as_str()));
+            assert_eq!(deep, ["B", "C", "D"]);
+        }
+    }
+
+    #[test]
+    fn every_node_comes_out_once_around_a_cycle() {
+        let structure = structure(&[("A", "B"), ("B", "C"), ("C", "A"), ("A", "C")]);
+        let deep = sorted(structure.descendants("A").unwrap().depth_first().max_depth(5).map(|node| node.key.as_str()));
+        assert_eq!(deep, ["B", "C"]);
+        let all = sorted(structure.descendants("A").unwrap().depth_first().map(|node| node.key.as_str()));
+        assert_eq!(all, ["B", "C"]);
+    }
+
+    #[test]
+    fn criss_cross_merge_has_two_lowest_common_ancestors() {
+        // X and Y both merge A and B, so neither A nor B is below the other
+        let structure = structure(&[("R", "A"), ("R", "B"), ("A", "X"), ("B", "X"), ("A", "Y"), ("B", "Y"), ("X", "Z")]);
+        assert_eq!(structure.lowest_common_ancestors("X", "Y", None).unwrap(), ["A", "B"]);
+        assert_eq!(structure.lowest_common_ancestors("Z", "Y", Some(Direction::Parents)).unwrap(), ["A", "B"]);
+        // a node counts as its own ancestor
+        assert_eq!(structure.lowest_common_ancestors("A", "Z", None).unwrap(), ["A"]);
+        assert_eq!(structure.lowest_common_ancestors("A", "B", Some(Direction::Children)).unwrap(), ["X", "Y"]);
+        assert_eq!(structure.lowest_common_ancestors("R", "Z", Some(Direction::Children)).unwrap(), ["Z"]);
+
+        assert!(matches!(
+            structure.lowest_common_ancestors("X", "Y", Some(Direction::Either)),
+            Err(MapRootError::UnsupportedDirection(Direction::Either))
+        ));
+        assert!(matches!(structure.lowest_common_ancestors("X", "missing", None), Err(MapRootError::NotFound(_))));
+    }
+
+    #[test]
+    fn shortest_path_takes_the_short_way_round_in_the_direction_asked() {
+        let structure = structure(&[("A", "B"), ("B", "C"), ("C", "D"), ("A", "D"), ("E", "C")]);
+        assert_eq!(structure.shortest_path("A", "D", None).unwrap().unwrap(), ["A", "D"]);
+        assert_eq!(structure.shortest_path("B", "D", Some(Direction::Children)).unwrap().unwrap(), ["B", "C", "D"]);
+        assert_eq!(structure.shortest_path("D", "A", Some(Direction::Parents)).unwrap().unwrap(), ["D", "A"]);
+        assert_eq!(structure.shortest_path("D", "A", None).unwrap(), None);
+        // E and B only meet at C, which is a child of both
+        assert_eq!(structure.shortest_path("E", "B", None).unwrap(), None);
+        assert_eq!(structure.shortest_path("E", "B", Some(Direction::Either)).unwrap().unwrap(), ["E", "C", "B"]);
+        assert_eq!(structure.shortest_path("C", "C", None).unwrap().unwrap(), ["C"]);
+    }
+
+    #[test]
+    fn topo_levels_group_nodes_by_their_last_parent_and_report_a_cycle() {
+        let edges = [("A", "B"), ("A", "C"), ("B", "D"), ("C", "D"), ("E", "D"), ("A", "D")];
+        let dag = structure(&edges);
+        let levels: Vec<Vec<&str>> = dag.topo_levels().unwrap().iter().map(|level| level.iter().map(|node| node.key.as_str()).collect()).collect();
+        assert_eq!(levels, [vec!["A", "E"], vec!["B", "C"], vec!["D"]]);
+        let order: Vec<&str> = dag.topo_order().unwrap().iter().map(|node| node.key.as_str()).collect();
+        assert_eq!(order, ["A", "E", "B", "C", "D"]);
+
+        // D hangs the cycle X -> Y -> Z -> X off the rest, only the cycle itself is reported
+        let mut edges = edges.to_vec();
+        edges.extend([("D", "X"), ("X", "Y"), ("Y", "Z"), ("Z", "X")]);
+        let cyclic = structure(&edges);
+        let Err(MapRootError::Cycle(cycle)) = cyclic.topo_levels() else { panic!("X, Y and Z form a cycle") };
+        assert_eq!(cycle.first(), cycle.last());
+        assert_eq!(sorted(cycle[1..].iter().map(String::as_str)), ["X", "Y", "Z"]);
+        assert!(matches!(cyclic.topo_order(), Err(MapRootError::Cycle(_))));
+
+        let looped = structure(&[("A", "A")]);
+        assert!(matches!(looped.topo_order(), Err(MapRootError::Cycle(cycle)) if cycle == ["A", "A"]));
+    }
+}
@@ -0,0 +1,412 @@
+// the write-ahead log for the database
+// every mutation that goes through the database is appended here before the caller gets an answer
+// on open the log is replayed on top of the last saved snapshot so nothing since that save is lost
+// the log is a Log container as described in format.rs, so every record carries its length and a crc32
+// the payload of a record is the sequence number of the operation followed by the operation itself,
+// a snapshot remembers the last sequence number it contains so older records are never applied twice
+
+use crate::format::{self, decode, encode, FileKind, FormatError, ValueTag};
+use crate::error::Result;
+use crate::node::{Edge, NewNode};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+// one logical operation on the database
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WalOp<T> {
+    AddStructure { name: String, snapshot: Vec<u8> },
+    AddNode { structure: String, key: String, value: T, parents: Vec<String>, children: Vec<String>, edges: Vec<(String, Edge)> },
+    DeleteNode { structure: String, key: String },
+    RemoveNode { structure: String, key: String },
+    Link { structure: String, parent: String, child: String },
+    EditValue { structure: String, key: String, value: T },
+    Unlink { structure: String, parent: String, child: String },
+    Upsert { structure: String, key: String, value: T, parents: Vec<String>, children: Vec<String>, edges: Vec<(String, Edge)> },
+    Replace { structure: String, key: String, value: T, parents: Vec<String>, children: Vec<String>, edges: Vec<(String, Edge)> },
+    Rename { structure: String, old_key: String, new_key: String },
+    LinkWith { structure: String, parent: String, child: String, edge: Edge },
+    SetRoot { structure: String, root: Option<String> },
+    MoveChild { structure: String, child: String, from: String, to: String },
+}
+
+// both database handles build their operations here, then run and log the same value, see database::execute
+impl<T: Clone> WalOp<T> {
+    pub(crate) fn structure(&self) -> &str {
+        // the name of the structure the operation changes, or adds
+        match self {
+            WalOp::AddStructure { name, .. } => name,
+            WalOp::AddNode { structure, .. }
+            | WalOp::DeleteNode { structure, .. }
+            | WalOp::RemoveNode { structure, .. }
+            | WalOp::Link { structure, .. }
+            | WalOp::EditValue { structure, .. }
+            | WalOp::Unlink { structure, .. }
+            | WalOp::Upsert { structure, .. }
+            | WalOp::Replace { structure, .. }
+            | WalOp::Rename { structure, .. }
+            | WalOp::LinkWith { structure, .. }
+            | WalOp::SetRoot { structure, .. }
+            | WalOp::MoveChild { structure, .. } => structure,
+        }
+    }
+
+    pub(crate) fn add_node(structure: &str, node: NewNode<T>) -> Self {
+        let NewNode { key, value, parents, children, edges } = node;
+        WalOp::AddNode { structure: structure.to_string(), key, value, parents, children, edges }
+    }
+
+    pub(crate) fn upsert(structure: &str, node: NewNode<T>) -> Self {
+        let NewNode { key, value, parents, children, edges } = node;
+        WalOp::Upsert { structure: structure.to_string(), key, value, parents, children, edges }
+    }
+
+    pub(crate) fn replace(structure: &str, node: NewNode<T>) -> Self {
+        let NewNode { key, value, parents, children, edges } = node;
+        WalOp::Replace { structure: structure.to_string(), key, value, parents, children, edges }
+    }
+
+    pub(crate) fn delete_node(structure: &str, key: &str) -> Self {
+        WalOp::DeleteNode { structure: structure.to_string(), key: key.to_string() }
+    }
+
+    pub(crate) fn remove_node(structure: &str, key: &str) -> Self {
+        WalOp::RemoveNode { structure: structure.to_string(), key: key.to_string() }
+    }
+
+    pub(crate) fn link(structure: &str, parent: &str, child: &str) -> Self {
+        WalOp::Link { structure: structure.to_string(), parent: parent.to_string(), child: child.to_string() }
+    }
+
+    pub(crate) fn link_with(structure: &str, parent: &str, child: &str, edge: Edge) -> Self {
+        WalOp::LinkWith { structure: structure.to_string(), parent: parent.to_string(), child: child.to_string(), edge }
+    }
+
+    pub(crate) fn unlink(structure: &str, parent: &str, child: &str) -> Self {
+        WalOp::Unlink { structure: structure.to_string(), parent: parent.to_string(), child: child.to_string() }
+    }
+
+    pub(crate) fn edit_value(structure: &str, key: &str, value: T) -> Self {
+        WalOp::EditValue { structure: structure.to_string(), key: key.to_string(), value }
+    }
+
+    pub(crate) fn rename(structure: &str, old_key: &str, new_key: &str) -> Self {
+        WalOp::Rename { structure: structure.to_string(), old_key: old_key.to_string(), new_key: new_key.to_string() }
+    }
+
+    pub(crate) fn set_root(structure: &str, root: Option<&str>) -> Self {
+        WalOp::SetRoot { structure: structure.to_string(), root: root.map(str::to_string) }
+    }
+
+    pub(crate) fn move_child(structure: &str, child: &str, from: &str, to: &str) -> Self {
+        WalOp::MoveChild { structure: structure.to_string(), child: child.to_string(), from: from.to_string(), to: to.to_string() }
+    }
+}
+
+// names the operation in errors, values and payloads are left out since T does not have to be printable
+impl<T> fmt::Display for WalOp<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalOp::AddStructure { name, .. } => write!(f, "add structure {}", name),
+            WalOp::AddNode { structure, key, .. } => write!(f, "add node {} to {}", key, structure),
+            WalOp::DeleteNode { structure, key } => write!(f, "delete node {} from {}", key, structure),
+            WalOp::RemoveNode { structure, key } => write!(f, "remove node {} from {}", key, structure),
+            WalOp::Link { structure, parent, child } | WalOp::LinkWith { structure, parent, child, .. } => write!(f, "link {} to {} in {}", parent, child, structure),
+            WalOp::EditValue { structure, key, .. } => write!(f, "edit node {} in {}", key, structure),
+            WalOp::Unlink { structure, parent, child } => write!(f, "unlink {} from {} in {}", parent, child, structure),
+            WalOp::Upsert { structure, key, .. } => write!(f, "upsert node {} in {}", key, structure),
+            WalOp::Replace { structure, key, .. } => write!(f, "replace node {} in {}", key, structure),
+            WalOp::Rename { structure, old_key, new_key } => write!(f, "rename {} to {} in {}", old_key, new_key, structure),
+            WalOp::SetRoot { structure, root: Some(root) } => write!(f, "set the root of {} to {}", structure, root),
+            WalOp::SetRoot { structure, root: None } => write!(f, "clear the root of {}", structure),
+            WalOp::MoveChild { structure, child, from, to } => write!(f, "move {} from {} to {} in {}", child, from, to, structure),
+        }
+    }
+}
+
+// an operation read back from the log with the sequence number it was logged under
+pub type Logged<T> = (u64, WalOp<T>);
+
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    next_lsn: u64,   // sequence number handed to the next appended operation
+    header_len: u64, // bytes taken by the file header, which a reset keeps
+    len: u64,        // bytes currently in the file
+    ops: u64,        // records currently in the log
+    failed: bool,    // a failed append could not be cut back off the file, so nothing more can go after it
+}
+
+impl WriteAheadLog {
+    pub fn open<T: DeserializeOwned + ValueTag, P: AsRef<Path>>(path: P, checkpoint_lsn: u64) -> Result<(Self, Vec<Logged<T>>)> {
+        // open the log for appending, creating it if it does not exist yet
+        // gives back every intact operation newer than the checkpoint, with its sequence number, so the caller
+        // can replay them in order
+        // a record that is cut short, or a last record that fails its checksum, can only come from a write
+        // that never finished, so the log is truncated right before it
+        // a record that was written completely and damaged afterwards, one that fails its checksum with more
+        // records after it or one that does not decode, is reported as corruption instead of dropped along
+        // with everything after it, and so is a damaged file header
+        let path = path.as_ref().to_path_buf();
+        let mut data: Vec<u8> = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => { file.read_to_end(&mut data)?; }
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let header_len = match format::read_header(&data, FileKind::Log, &format::value_type_tag::<T>()) {
+            Ok(header_len) => header_len,
+            Err(FormatError::Truncated { .. }) => {
+                // a brand new log, or one whose header never fully made it to disk so it cannot hold any records
+                data.clear();
+                let mut header: Vec<u8> = Vec::new();
+                format::write_header(&mut header, FileKind::Log, &format::value_type_tag::<T>());
+                file.set_len(0)?;
+                file.write_all(&header)?;
+                file.sync_all()?;
+                header.len()
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ops: Vec<Logged<T>> = Vec::new();
+        let mut last_lsn = checkpoint_lsn;
+        let mut records = 0;
+        let mut offset = header_len;
+        while offset < data.len() {
+            let (lsn, op, next) = match Self::read_record(&data, offset) {
+                Ok(record) => record,
+                Err(FormatError::Truncated { .. }) => break,
+                Err(FormatError::ChecksumMismatch { .. }) if format::record_end(&data, offset) == Some(data.len()) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if lsn > checkpoint_lsn {
+                ops.push((lsn, op));
+            }
+            last_lsn = last_lsn.max(lsn);
+            records += 1;
+            offset = next;
+        }
+
+        if offset < data.len() {
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+        let log = WriteAheadLog { path, file, next_lsn: last_lsn + 1, header_len: header_len as u64, len: offset.max(header_len) as u64, ops: records, failed: false };
+        Ok((log, ops))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        // bytes taken by the records, not counting the file header
+        self.len - self.header_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops == 0
+    }
+
+    pub fn ops(&self) -> u64 {
+        self.ops
+    }
+
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+
+    pub fn append<T: Serialize>(&mut self, op: &WalOp<T>) -> Result<u64> {
+        // the whole record goes out in a single write and is synced before returning
+        // returns the sequence number the operation was logged under
+        // if the write fails whatever part of it reached the file is cut off again, otherwise the next open
+        // would stop at the torn record and drop every record appended after it
+        if self.failed {
+            return Err(io::Error::other("an earlier append to the log could not be undone, reopen the database").into());
+        }
+        let lsn = self.next_lsn;
+        let payload = encode(&(lsn, op), "log record")?;
+        let mut record: Vec<u8> = Vec::new();
+        format::write_record(&mut record, &payload)?;
+
+        if let Err(e) = self.file.write_all(&record).and_then(|_| self.file.sync_data()) {
+            if self.file.set_len(self.len).and_then(|_| self.file.sync_all()).is_err() {
+                self.failed = true;
+            }
+            return Err(e.into());
+        }
+        self.next_lsn += 1;
+        self.len += record.len() as u64;
+        self.ops += 1;
+        Ok(lsn)
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        // drop every record, used once the log has been folded into a snapshot
+        // sequence numbers keep counting up so a stale snapshot can still tell which records it is missing
+        self.file.set_len(self.header_len)?;
+        self.file.sync_all()?;
+        self.len = self.header_len;
+        self.ops = 0;
+        Ok(())
+    }
+
+    fn read_record<T: DeserializeOwned>(data: &[u8], offset: usize) -> std::result::Result<(u64, WalOp<T>, usize), FormatError> {
+        // returns the sequence number and operation at the offset and where the next record starts
+        let (payload, next) = format::read_record(data, offset, "log record")?;
+        let (lsn, op): (u64, WalOp<T>) = decode(payload, "log record")?;
+        Ok((lsn, op, next))
+    }
+}
+
+#[cfg(test)]
+impl WriteAheadLog {
+    pub(crate) fn break_writes(&mut self) {
+        // swap in a read only handle so every write and truncate from here on fails
+        self.file = File::open(&self.path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MapRootError;
+    use bincode::serialize;
+    use std::fs;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maprootdb-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("test.wal")
+    }
+
+    fn link(n: u32) -> WalOp<u32> {
+        WalOp::Link { structure: "s".to_string(), parent: n.to_string(), child: (n + 1).to_string() }
+    }
+
+    fn open(path: &Path, checkpoint_lsn: u64) -> (WriteAheadLog, Vec<WalOp<u32>>) {
+        let (log, ops) = WriteAheadLog::open::<u32, _>(path, checkpoint_lsn).unwrap();
+        (log, ops.into_iter().map(|(_, op)| op).collect())
+    }
+
+    #[test]
+    fn replay_skips_records_covered_by_the_checkpoint() {
+        let path = scratch("checkpoint");
+        let (mut log, ops) = open(&path, 0);
+        assert!(ops.is_empty());
+        for n in 1..=3 {
+            assert_eq!(log.append(&link(n)).unwrap(), n as u64);
+        }
+        drop(log);
+
+        let (_, ops) = open(&path, 0);
+        assert_eq!(ops, vec![link(1), link(2), link(3)]);
+        let (log, ops) = open(&path, 2);
+        assert_eq!(ops, vec![link(3)]);
+        assert_eq!(log.last_lsn(), 3);
+        // a checkpoint newer than every record keeps the sequence numbers counting from it
+        let (log, ops) = open(&path, 10);
+        assert!(ops.is_empty());
+        assert_eq!(log.last_lsn(), 10);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off_and_appends_continue_after_it() {
+        let path = scratch("torn");
+        let (mut log, _) = open(&path, 0);
+        log.append(&link(1)).unwrap();
+        log.append(&link(2)).unwrap();
+        drop(log);
+        let intact = fs::metadata(&path).unwrap().len();
+
+        // half of a third record, as left behind by a crash in the middle of a write
+        let mut record = Vec::new();
+        format::write_record(&mut record, &serialize(&(3u64, link(3))).unwrap()).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        let (mut log, ops) = open(&path, 0);
+        assert_eq!(ops, vec![link(1), link(2)]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+        log.append(&link(4)).unwrap();
+        drop(log);
+        let (_, ops) = open(&path, 0);
+        assert_eq!(ops, vec![link(1), link(2), link(4)]);
+    }
+
+    #[test]
+    fn last_record_failing_its_checksum_is_cut_off() {
+        let path = scratch("checksum");
+        let (mut log, _) = open(&path, 0);
+        log.append(&link(1)).unwrap();
+        log.append(&link(2)).unwrap();
+        drop(log);
+
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        let (_, ops) = open(&path, 0);
+        assert_eq!(ops, vec![link(1)]);
+    }
+
+    #[test]
+    fn damaged_record_with_more_after_it_is_corruption() {
+        let path = scratch("damaged");
+        let (mut log, _) = open(&path, 0);
+        log.append(&link(1)).unwrap();
+        let first_end = fs::metadata(&path).unwrap().len() as usize;
+        log.append(&link(2)).unwrap();
+        drop(log);
+
+        let mut data = fs::read(&path).unwrap();
+        data[first_end - 1] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        let result = WriteAheadLog::open::<u32, _>(&path, 0);
+        assert!(matches!(result, Err(MapRootError::Corrupt(FormatError::ChecksumMismatch { .. }))));
+        // nothing was cut off, so the second record is still there to recover by hand
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn record_that_does_not_decode_is_corruption() {
+        let path = scratch("decode");
+        let (mut log, _) = open(&path, 0);
+        log.append(&link(1)).unwrap();
+        drop(log);
+
+        // a complete record with a valid checksum that is not a log operation
+        let mut record = Vec::new();
+        format::write_record(&mut record, &[0xff; 3]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+
+        let result = WriteAheadLog::open::<u32, _>(&path, 0);
+        assert!(matches!(result, Err(MapRootError::Corrupt(FormatError::Decode { .. }))));
+    }
+
+    #[test]
+    fn failed_append_leaves_earlier_records_and_refuses_more() {
+        let path = scratch("failed");
+        let (mut log, _) = open(&path, 0);
+        log.append(&link(1)).unwrap();
+        log.break_writes();
+        assert!(log.append(&link(2)).is_err());
+        // the read only handle can not truncate either, so the log gives up on appending
+        assert!(log.failed);
+        assert!(log.append(&link(3)).is_err());
+        drop(log);
+
+        let (_, ops) = open(&path, 0);
+        assert_eq!(ops, vec![link(1)]);
+    }
+}